@@ -0,0 +1,438 @@
+use crate::{Diagnostic, Diagnostics, Dialect, Instruction, ParseError, Program};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+fn is_label(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+// the position of the relative jump argument, which may also be a label
+fn jump_argument(operation: &str) -> Option<usize> {
+    match operation {
+        "jmp" | "nop" => Some(0),
+        "jz" | "jnz" => Some(1),
+        _ => None,
+    }
+}
+
+// the 1-based column (in characters) where part, a subslice of line, starts
+fn column(line: &str, part: &str) -> usize {
+    let offset = part.as_ptr() as usize - line.as_ptr() as usize;
+    line[..offset].chars().count() + 1
+}
+
+/// Assemble a program that may contain `#` comments, blank lines,
+/// `name:` labels (on their own line or before an instruction)
+/// and label names instead of relative jump arguments (e.g. `jmp name`).
+/// A `.dialect 2020|extended` directive (anywhere) assembles the whole program in that dialect
+/// instead of the given one.
+/// All errors in the source are reported, each with its line and column.
+pub fn assemble(source: &str, mut dialect: Dialect) -> Result<Program, Diagnostics> {
+    let mut diagnostics = Vec::new();
+    let mut labels = HashMap::new();
+    let mut lines = Vec::new();
+    for (line_index, line) in source.lines().enumerate() {
+        let error = |part: &str, error| Diagnostic {
+            line: line_index + 1,
+            column: column(line, part),
+            text: part.to_owned(),
+            error,
+        };
+        let mut code = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        }
+        .trim();
+        while let Some(colon) = code.find(':') {
+            let label = code[..colon].trim();
+            if !is_label(label) {
+                diagnostics.push(error(label, ParseError::BadLabel(label.to_owned())));
+            } else if labels.insert(label, lines.len()).is_some() {
+                diagnostics.push(error(label, ParseError::DuplicateLabel(label.to_owned())));
+            }
+            code = code[colon + 1..].trim();
+        }
+        if code.is_empty() {
+            continue;
+        }
+        if let Some(name) = code.strip_prefix(".dialect") {
+            let name = name.trim();
+            match name.parse() {
+                Ok(directive) => dialect = directive,
+                Err(parse_error) => diagnostics.push(error(name, parse_error)),
+            }
+            continue;
+        }
+        lines.push((line_index, line, code));
+    }
+
+    let mut instructions = Vec::with_capacity(lines.len());
+    for (index, &(line_index, line, code)) in lines.iter().enumerate() {
+        let error = |part: &str, error| Diagnostic {
+            line: line_index + 1,
+            column: column(line, part),
+            text: part.to_owned(),
+            error,
+        };
+        let words: Vec<&str> = code.split_whitespace().collect();
+        let operation = words[0];
+        let mut arguments: Vec<String> = words[1..].iter().map(|&word| word.to_owned()).collect();
+        if let Some(position) = jump_argument(operation) {
+            if arguments.len() == position + 1 && is_label(&arguments[position]) {
+                let label = words[position + 1];
+                match labels.get(label) {
+                    Some(&target) => {
+                        arguments[position] = (target as i64 - index as i64).to_string();
+                    }
+                    None => {
+                        diagnostics.push(error(label, ParseError::UnknownLabel(label.to_owned())));
+                        continue;
+                    }
+                }
+            }
+        }
+        let arguments: Vec<&str> = arguments.iter().map(String::as_str).collect();
+        match Instruction::parse_words(operation, &arguments, dialect) {
+            Ok(instruction) => instructions.push(instruction),
+            Err((0, parse_error)) => diagnostics.push(error(code, parse_error)),
+            Err((word, parse_error)) => diagnostics.push(error(words[word], parse_error)),
+        }
+    }
+
+    if diagnostics.is_empty() {
+        Ok(Program {
+            instructions,
+            dialect,
+        })
+    } else {
+        diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+        Err(Diagnostics(diagnostics))
+    }
+}
+
+fn jump_target(instruction: Instruction, index: usize, len: usize) -> Option<usize> {
+    match instruction {
+        Instruction::JMP(argument)
+        | Instruction::JZ(_, argument)
+        | Instruction::JNZ(_, argument) => {
+            let target = (index as i64).saturating_add(argument);
+            if target >= 0 && target <= len as i64 {
+                Some(target as usize)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Write the program with a label (`l<index>:`) before every jump target
+/// and the jumps referring to those labels instead of relative arguments.
+/// Jumps out of the program (other than to its end) keep their relative argument.
+pub fn write_labelled(program: &Program, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let len = program.instructions.len();
+    let targets: BTreeSet<usize> = program
+        .instructions
+        .iter()
+        .enumerate()
+        .filter_map(|(index, &instruction)| jump_target(instruction, index, len))
+        .collect();
+    for (index, &instruction) in program.instructions.iter().enumerate() {
+        if targets.contains(&index) {
+            writeln!(f, "l{}:", index)?;
+        }
+        match (instruction, jump_target(instruction, index, len)) {
+            (Instruction::JMP(_), Some(target)) => writeln!(f, "jmp l{}", target)?,
+            (Instruction::JZ(register, _), Some(target)) => {
+                writeln!(f, "jz {} l{}", register, target)?
+            }
+            (Instruction::JNZ(register, _), Some(target)) => {
+                writeln!(f, "jnz {} l{}", register, target)?
+            }
+            _ => writeln!(f, "{}", instruction)?,
+        }
+    }
+    if targets.contains(&len) {
+        writeln!(f, "l{}:", len)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Register;
+    use pretty_assertions::assert_eq;
+
+    fn assemble_2020(source: &str) -> Result<Program, Vec<ParseError>> {
+        errors(source, Dialect::Aoc2020)
+    }
+
+    fn errors(source: &str, dialect: Dialect) -> Result<Program, Vec<ParseError>> {
+        assemble(source, dialect).map_err(|Diagnostics(diagnostics)| {
+            diagnostics
+                .into_iter()
+                .map(|diagnostic| diagnostic.error)
+                .collect()
+        })
+    }
+
+    fn sample_program() -> Program {
+        Program::new(vec![
+            Instruction::NOP(0),
+            Instruction::ACC(1),
+            Instruction::JMP(4),
+            Instruction::ACC(3),
+            Instruction::JMP(-3),
+            Instruction::ACC(-99),
+            Instruction::ACC(1),
+            Instruction::JMP(-4),
+            Instruction::ACC(6),
+        ])
+    }
+
+    #[test]
+    fn test_assemble_labels_and_comments() {
+        assert_eq!(
+            assemble_2020(
+                "
+# the sample program from the puzzle
+nop +0
+loop: acc +1
+jmp skip   # forward jump
+
+back:
+acc +3
+jmp loop
+acc -99
+skip:
+acc +1
+jmp back
+acc +6
+"
+            )
+            .unwrap(),
+            sample_program()
+        );
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert_eq!(
+            assemble_2020("jmp nowhere"),
+            Err(vec![ParseError::UnknownLabel("nowhere".to_owned())])
+        );
+        assert_eq!(
+            assemble_2020("a:\na: nop +0"),
+            Err(vec![ParseError::DuplicateLabel("a".to_owned())])
+        );
+        assert_eq!(
+            assemble_2020("1a: nop +0"),
+            Err(vec![ParseError::BadLabel("1a".to_owned())])
+        );
+        assert_eq!(
+            assemble_2020("div +2"),
+            Err(vec![ParseError::UnknownOperation("div".to_owned())])
+        );
+    }
+
+    #[test]
+    fn test_assemble_diagnostics() {
+        let diagnostics = |source| match assemble(source, Dialect::Aoc2020) {
+            Err(Diagnostics(diagnostics)) => diagnostics
+                .into_iter()
+                .map(|diagnostic| (diagnostic.line, diagnostic.column, diagnostic.text))
+                .collect(),
+            Ok(_program) => vec![],
+        };
+        assert_eq!(
+            diagnostics(
+                "nop +0\n  acc  +x\n\t1a: jmp end\nfoo +1 # bar\njmp\nacc +1 +2\r\nä: acc 1"
+            ),
+            vec![
+                (2, 8, "+x".to_owned()),
+                (3, 2, "1a".to_owned()),
+                (3, 10, "end".to_owned()),
+                (4, 1, "foo +1".to_owned()),
+                (5, 1, "jmp".to_owned()),
+                (6, 1, "acc +1 +2".to_owned()),
+                (7, 1, "ä".to_owned()),
+            ]
+        );
+        assert_eq!(
+            assemble("nop +0\n  acc  +x", Dialect::Aoc2020)
+                .unwrap_err()
+                .to_string(),
+            "2:8: BadArgument(ParseIntError { kind: InvalidDigit }) (`+x`)"
+        );
+        // never panics, whatever the input
+        for source in &[
+            "",
+            "\n",
+            ":",
+            "a:",
+            "ac",
+            "acc",
+            "acc ",
+            "jmp +",
+            "#",
+            "jz",
+            "acc +99999999999999999999",
+        ] {
+            let _ = assemble(source, Dialect::Extended);
+        }
+    }
+
+    #[test]
+    fn test_assemble_whitespace_and_crlf() {
+        assert_eq!(
+            assemble_2020("nop +0\r\n\t acc\t+1 \r\n\r\n  loop:jmp   loop\r\n"),
+            Ok(Program::new(vec![
+                Instruction::NOP(0),
+                Instruction::ACC(1),
+                Instruction::JMP(0)
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            sample_program().to_string(),
+            "\
+nop +0
+acc +1
+jmp +4
+acc +3
+jmp -3
+acc -99
+acc +1
+jmp -4
+acc +6
+"
+        );
+        assert_eq!(
+            format!("{:#}", sample_program()),
+            "\
+nop +0
+l1:
+acc +1
+jmp l6
+l3:
+acc +3
+jmp l1
+acc -99
+l6:
+acc +1
+jmp l3
+acc +6
+"
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let operations = [Instruction::ACC, Instruction::JMP, Instruction::NOP];
+        let instructions: Vec<Instruction> = operations
+            .iter()
+            .flat_map(|&operation| (-4..=4).map(operation))
+            .collect();
+        for &a in &instructions {
+            for &b in &instructions {
+                for &c in &instructions {
+                    let program = Program::new(vec![a, b, c]);
+                    assert_eq!(program.to_string().parse(), Ok(program.clone()));
+                    assert_eq!(format!("{:#}", program).parse(), Ok(program.clone()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_dialects() {
+        let source = "
+            add r1 +3
+            loop: mul acc +2
+            out acc
+            add r1 -1
+            jnz r1 loop
+            jz acc +2
+            hlt
+        ";
+        assert_eq!(
+            assemble_2020(source),
+            Err(["add", "mul", "out", "add", "jnz", "jz", "hlt"]
+                .iter()
+                .map(|&operation| ParseError::NotInDialect(operation.to_owned(), Dialect::Aoc2020))
+                .collect())
+        );
+        let program = assemble(source, Dialect::Extended).unwrap();
+        assert_eq!(
+            program.instructions,
+            vec![
+                Instruction::ADD(Register::R1, 3),
+                Instruction::MUL(Register::ACC, 2),
+                Instruction::OUT(Register::ACC),
+                Instruction::ADD(Register::R1, -1),
+                Instruction::JNZ(Register::R1, -3),
+                Instruction::JZ(Register::ACC, 2),
+                Instruction::HLT,
+            ]
+        );
+        assert_eq!(
+            format!("{:#}", program),
+            "\
+.dialect extended
+add r1 +3
+l1:
+mul acc +2
+out acc
+add r1 -1
+jnz r1 l1
+jz acc l7
+hlt
+l7:
+"
+        );
+        assert_eq!(
+            assemble(&program.to_string(), Dialect::Extended),
+            Ok(program.clone())
+        );
+        assert_eq!(
+            assemble(&format!("{:#}", program), Dialect::Extended),
+            Ok(program.clone())
+        );
+        // the directive carries the dialect through printing and parsing with the default one
+        assert_eq!(program.to_string().parse(), Ok(program.clone()));
+        assert_eq!(format!("{:#}", program).parse(), Ok(program));
+        // even without extended instructions, which still loop differently
+        let program = assemble("acc +1\njmp -1", Dialect::Extended).unwrap();
+        assert_eq!(".dialect extended\nacc +1\njmp -1\n", program.to_string());
+        assert_eq!(program.to_string().parse(), Ok(program.clone()));
+        // and it overrides the dialect parsing starts with
+        let program = Program::new(vec![Instruction::ACC(1)]);
+        assert_eq!(
+            assemble(".dialect 2020\nacc +1", Dialect::Extended),
+            Ok(program)
+        );
+        assert_eq!(
+            errors("# a comment first\n.dialect 2021\n", Dialect::Aoc2020),
+            Err(vec![ParseError::UnknownDialect("2021".to_owned())])
+        );
+
+        assert_eq!(
+            errors("out r4", Dialect::Extended),
+            Err(vec![ParseError::UnknownRegister("r4".to_owned())])
+        );
+        assert_eq!(
+            errors("jz r1", Dialect::Extended),
+            Err(vec![ParseError::WrongArgumentCount("jz".to_owned())])
+        );
+    }
+}
@@ -0,0 +1,377 @@
+use crate::{Dialect, FaultPolicy, Interpreter, StepOutcome};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+pub struct Debugger<R, W> {
+    interpreter: Interpreter,
+    breakpoints: BTreeSet<usize>,
+    finished: Option<StepOutcome>,
+    input: R,
+    output: W,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Command {
+    Break(usize),
+    Delete(usize),
+    Breakpoints,
+    Step(usize),
+    Back(usize),
+    Rewind(usize),
+    Continue,
+    Print,
+    Seen,
+    Trace,
+    SetAccumulator(i64),
+    SetInstructionPointer(usize),
+    SetFaultPolicy(FaultPolicy),
+    Help,
+    Quit,
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Self, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["b", ..] | ["break", ..] => Ok(Command::Break(argument(words.get(1))?)),
+            ["d", ..] | ["delete", ..] => Ok(Command::Delete(argument(words.get(1))?)),
+            ["breakpoints"] => Ok(Command::Breakpoints),
+            ["s"] | ["step"] => Ok(Command::Step(1)),
+            ["s", ..] | ["step", ..] => Ok(Command::Step(argument(words.get(1))?)),
+            ["back"] => Ok(Command::Back(1)),
+            ["back", ..] => Ok(Command::Back(argument(words.get(1))?)),
+            ["rewind", ..] => Ok(Command::Rewind(argument(words.get(1))?)),
+            ["c"] | ["continue"] => Ok(Command::Continue),
+            ["p"] | ["print"] => Ok(Command::Print),
+            ["seen"] => Ok(Command::Seen),
+            ["trace"] => Ok(Command::Trace),
+            ["set", "acc", ..] => Ok(Command::SetAccumulator(argument(words.get(2))?)),
+            ["set", "ip", ..] => Ok(Command::SetInstructionPointer(argument(words.get(2))?)),
+            ["set", "policy", ..] => Ok(Command::SetFaultPolicy(argument(words.get(2))?)),
+            ["h"] | ["help"] => Ok(Command::Help),
+            ["q"] | ["quit"] => Ok(Command::Quit),
+            _ => Err(format!("unknown command: {}", line.trim())),
+        }
+    }
+}
+
+fn argument<T: FromStr>(word: Option<&&str>) -> Result<T, String>
+where
+    T::Err: fmt::Display,
+{
+    let word = word.ok_or_else(|| "missing argument".to_owned())?;
+    word.parse()
+        .map_err(|e| format!("bad argument {}: {}", word, e))
+}
+
+const HELP: &str = "\
+break N        set a breakpoint on instruction N
+delete N       remove the breakpoint on instruction N
+breakpoints    list all breakpoints
+step [N]       execute N instructions (default 1)
+back [N]       undo the last N instructions (default 1)
+rewind N       go back to the state before step N (0 = start of the program)
+continue       run until a breakpoint, infinite loop or termination
+print          show accumulator and instruction pointer
+seen           list the instructions executed so far
+trace          print a table of all instructions executed so far
+set acc N      set the accumulator to N
+set ip N       set the instruction pointer to N
+set policy P   handle faults by P: trap (stop), wrap or clamp
+help           show this help
+quit           exit the debugger";
+
+impl<R: BufRead, W: Write> Debugger<R, W> {
+    pub fn new(mut interpreter: Interpreter, input: R, output: W) -> Self {
+        interpreter.keep_history();
+        Debugger {
+            interpreter,
+            breakpoints: BTreeSet::new(),
+            finished: None,
+            input,
+            output,
+        }
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut line = String::new();
+        loop {
+            write!(self.output, "(dbg) ")?;
+            self.output.flush()?;
+            line.clear();
+            if self.input.read_line(&mut line)? == 0 {
+                writeln!(self.output)?;
+                return Ok(());
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            match Command::parse(&line) {
+                Ok(Command::Quit) => return Ok(()),
+                Ok(command) => self.execute(command)?,
+                Err(message) => writeln!(self.output, "{}", message)?,
+            }
+        }
+    }
+
+    fn execute(&mut self, command: Command) -> io::Result<()> {
+        match command {
+            Command::Break(index) => {
+                self.breakpoints.insert(index);
+                writeln!(self.output, "breakpoint at {}", index)
+            }
+            Command::Delete(index) => {
+                if self.breakpoints.remove(&index) {
+                    writeln!(self.output, "deleted breakpoint at {}", index)
+                } else {
+                    writeln!(self.output, "no breakpoint at {}", index)
+                }
+            }
+            Command::Breakpoints => {
+                let breakpoints: Vec<String> =
+                    self.breakpoints.iter().map(usize::to_string).collect();
+                writeln!(self.output, "breakpoints: {}", breakpoints.join(" "))
+            }
+            Command::Step(count) => {
+                for _ in 0..count {
+                    if !self.step()? {
+                        break;
+                    }
+                }
+                self.print_state()
+            }
+            Command::Back(count) => {
+                for _ in 0..count {
+                    if self.interpreter.step_back().is_none() {
+                        if self.interpreter.steps() == 0 {
+                            writeln!(self.output, "already at the start")?;
+                        } else {
+                            writeln!(
+                                self.output,
+                                "no history before step {}",
+                                self.interpreter.steps()
+                            )?;
+                        }
+                        break;
+                    }
+                    self.finished = None;
+                }
+                self.print_state()
+            }
+            Command::Rewind(step) => {
+                if !self.interpreter.rewind(step) {
+                    writeln!(self.output, "no history back to step {}", step)?;
+                }
+                self.finished = None;
+                self.print_state()
+            }
+            Command::Continue => {
+                while self.step()? {
+                    if self
+                        .breakpoints
+                        .contains(&self.interpreter.instruction_pointer)
+                    {
+                        writeln!(
+                            self.output,
+                            "hit breakpoint at {}",
+                            self.interpreter.instruction_pointer
+                        )?;
+                        break;
+                    }
+                }
+                self.print_state()
+            }
+            Command::Print => self.print_state(),
+            Command::Seen => {
+                let seen: Vec<String> = self
+                    .interpreter
+                    .seen_instructions()
+                    .iter()
+                    .map(usize::to_string)
+                    .collect();
+                writeln!(self.output, "seen: {}", seen.join(" "))
+            }
+            Command::Trace => write!(self.output, "{}", self.interpreter.trace_table()),
+            Command::SetAccumulator(accumulator) => {
+                self.interpreter.accumulator = accumulator;
+                self.print_state()
+            }
+            Command::SetInstructionPointer(instruction_pointer) => {
+                self.interpreter.instruction_pointer = instruction_pointer;
+                self.finished = None;
+                self.print_state()
+            }
+            Command::SetFaultPolicy(fault_policy) => {
+                self.interpreter.fault_policy = fault_policy;
+                self.finished = None;
+                writeln!(self.output, "fault policy: {:?}", fault_policy)
+            }
+            Command::Help => writeln!(self.output, "{}", HELP),
+            Command::Quit => Ok(()),
+        }
+    }
+
+    /// Step the interpreter once, returning whether execution can continue.
+    fn step(&mut self) -> io::Result<bool> {
+        if let Some(outcome) = self.finished {
+            writeln!(self.output, "program already finished: {:?}", outcome)?;
+            return Ok(false);
+        }
+        match self.interpreter.step() {
+            StepOutcome::Continue => Ok(true),
+            outcome => {
+                writeln!(self.output, "{:?}", outcome)?;
+                self.finished = Some(outcome);
+                Ok(false)
+            }
+        }
+    }
+
+    fn print_state(&mut self) -> io::Result<()> {
+        let instruction = self
+            .interpreter
+            .program
+            .instructions
+            .get(self.interpreter.instruction_pointer);
+        let registers = match self.interpreter.program.dialect {
+            Dialect::Aoc2020 => String::new(),
+            Dialect::Extended => {
+                let [r1, r2, r3] = self.interpreter.registers;
+                format!(" r1={} r2={} r3={}", r1, r2, r3)
+            }
+        };
+        writeln!(
+            self.output,
+            "acc={}{} ip={} next={}",
+            self.interpreter.accumulator,
+            registers,
+            self.interpreter.instruction_pointer,
+            match instruction {
+                Some(instruction) => format!("{:?}", instruction),
+                None => "<end>".to_owned(),
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Instruction, Program};
+    use pretty_assertions::assert_eq;
+
+    fn sample_interpreter() -> Interpreter {
+        Interpreter::new(Program::new(vec![
+            Instruction::NOP(0),
+            Instruction::ACC(1),
+            Instruction::JMP(4),
+            Instruction::ACC(3),
+            Instruction::JMP(-3),
+            Instruction::ACC(-99),
+            Instruction::ACC(1),
+            Instruction::JMP(-4),
+            Instruction::ACC(6),
+        ]))
+    }
+
+    fn debug(script: &str) -> String {
+        let mut output = Vec::new();
+        Debugger::new(sample_interpreter(), script.as_bytes(), &mut output)
+            .run()
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_step_and_print() {
+        assert_eq!(
+            debug("step\nstep 2\nseen\nquit\n"),
+            "\
+(dbg) acc=0 ip=1 next=ACC(1)
+(dbg) acc=1 ip=6 next=ACC(1)
+(dbg) seen: 0 1 2
+(dbg) "
+        );
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        assert_eq!(
+            debug("break 4\nbreak 7\ndelete 7\nbreakpoints\ncontinue\ncontinue\nquit\n"),
+            "\
+(dbg) breakpoint at 4
+(dbg) breakpoint at 7
+(dbg) deleted breakpoint at 7
+(dbg) breakpoints: 4
+(dbg) hit breakpoint at 4
+acc=5 ip=4 next=JMP(-3)
+(dbg) InfiniteLoop(5)
+acc=5 ip=1 next=ACC(1)
+(dbg) "
+        );
+    }
+
+    #[test]
+    fn test_time_travel() {
+        assert_eq!(
+            debug("continue\nback 2\nrewind 1\ntrace\nback 2\n"),
+            "\
+(dbg) InfiniteLoop(5)
+acc=5 ip=1 next=ACC(1)
+(dbg) acc=2 ip=3 next=ACC(3)
+(dbg) acc=0 ip=1 next=ACC(1)
+(dbg) step\tindex\tinstruction\taccumulator_before\taccumulator_after
+0\t0\tnop +0\t0\t0
+(dbg) already at the start
+acc=0 ip=0 next=NOP(0)
+(dbg) \n"
+        );
+    }
+
+    #[test]
+    fn test_fault_policy() {
+        assert_eq!(
+            debug("set ip 4\nset acc 7\nset policy wrap\nstep\nset policy clamp\nset policy foo\n"),
+            "\
+(dbg) acc=0 ip=4 next=JMP(-3)
+(dbg) acc=7 ip=4 next=JMP(-3)
+(dbg) fault policy: Wrap
+(dbg) acc=7 ip=1 next=ACC(1)
+(dbg) fault policy: Clamp
+(dbg) bad argument foo: unknown fault policy: foo
+(dbg) \n"
+        );
+    }
+
+    #[test]
+    fn test_extended_registers() {
+        let program = Program::parse_dialect("add r2 +5\nout r2", Dialect::Extended).unwrap();
+        let mut output = Vec::new();
+        Debugger::new(Interpreter::new(program), "step\n".as_bytes(), &mut output)
+            .run()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+(dbg) acc=0 r1=0 r2=5 r3=0 ip=1 next=OUT(R2)
+(dbg) \n"
+        );
+    }
+
+    #[test]
+    fn test_edit_state() {
+        assert_eq!(
+            debug("set ip 8\nset acc 10\nstep\nstep\nfoo\n"),
+            "\
+(dbg) acc=0 ip=8 next=ACC(6)
+(dbg) acc=10 ip=8 next=ACC(6)
+(dbg) acc=16 ip=9 next=<end>
+(dbg) Terminate(16)
+acc=16 ip=9 next=<end>
+(dbg) unknown command: foo
+(dbg) \n"
+        );
+    }
+}
@@ -0,0 +1,262 @@
+use crate::repair::{self, RepairOptions};
+use crate::{Instruction, Interpreter, Program, StepOutcome};
+
+// SplitMix64, so that programs are reproducible from the seed without any dependencies
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn between(&mut self, low: i64, high: i64) -> i64 {
+        low + (self.next() % (high - low + 1) as u64) as i64
+    }
+
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i + 1));
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct GeneratorOptions {
+    pub len: usize,
+    // relative frequencies of the operations
+    pub acc_weight: u32,
+    pub jmp_weight: u32,
+    pub nop_weight: u32,
+}
+
+impl Default for GeneratorOptions {
+    // roughly like the puzzle inputs
+    fn default() -> Self {
+        GeneratorOptions {
+            len: 600,
+            acc_weight: 9,
+            jmp_weight: 5,
+            nop_weight: 2,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Generated {
+    pub program: Program,
+    pub corrupted_index: usize,
+    pub part1: i64,
+    pub part2: i64,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Block {
+    start: usize,
+    end: usize, // inclusive; the instruction at end leaves the block
+}
+
+const ATTEMPTS: usize = 1000;
+
+/// Generate a program that loops, and that terminates after toggling exactly one JMP/NOP.
+/// Returns None if no such program was found
+/// (only likely if the options make it impossible, e.g. without any JMP).
+pub fn generate(seed: u64, options: &GeneratorOptions) -> Option<Generated> {
+    let mut rng = Rng(seed);
+    (0..ATTEMPTS).find_map(|_attempt| attempt(&mut rng, options))
+}
+
+// The program is split into blocks that end in a jump.
+// Some blocks form a chain from the first instruction to the end of the program
+// (in random order), the others are junk that the repaired program never reaches.
+// One instruction on the chain is then corrupted so that it jumps back to an earlier part of the chain.
+// This does not rule out other repairs (e.g. through the junk),
+// so the result is checked and rejected if it does not have exactly one.
+fn attempt(rng: &mut Rng, options: &GeneratorOptions) -> Option<Generated> {
+    let len = options.len;
+    let total_weight = options.acc_weight + options.jmp_weight + options.nop_weight;
+    if len == 0 || total_weight == 0 {
+        return None;
+    }
+
+    let mut blocks = Vec::new();
+    let mut start = 0;
+    for index in 0..len {
+        if index == len - 1 || rng.below(total_weight as usize) < options.jmp_weight as usize {
+            blocks.push(Block { start, end: index });
+            start = index + 1;
+        }
+    }
+    let (mut chain, junk): (Vec<usize>, Vec<usize>) =
+        (1..blocks.len()).partition(|_block| rng.below(3) != 0);
+    rng.shuffle(&mut chain);
+    chain.insert(0, 0);
+
+    let mut instructions = vec![Instruction::NOP(0); len];
+    let random_offset =
+        |rng: &mut Rng, index: usize| rng.between(-(index as i64), (len - index) as i64);
+    for block in &blocks {
+        for (index, instruction) in instructions
+            .iter_mut()
+            .enumerate()
+            .take(block.end)
+            .skip(block.start)
+        {
+            *instruction = if rng.below((options.acc_weight + options.nop_weight).max(1) as usize)
+                < options.acc_weight as usize
+            {
+                Instruction::ACC(rng.between(-50, 50))
+            } else {
+                Instruction::NOP(random_offset(rng, index))
+            };
+        }
+    }
+    for (position, &block) in chain.iter().enumerate() {
+        let end = blocks[block].end;
+        let target = match chain.get(position + 1) {
+            Some(&next) => blocks[next].start,
+            None => len,
+        };
+        instructions[end] = if target == end + 1 && rng.below(2) == 0 {
+            Instruction::NOP(random_offset(rng, end))
+        } else {
+            Instruction::JMP(target as i64 - end as i64)
+        };
+    }
+    for &block in &junk {
+        let end = blocks[block].end;
+        instructions[end] = Instruction::JMP(random_offset(rng, end));
+    }
+
+    // corrupt a JMP/NOP in one of the last chain blocks (but not the very last one) to jump back into the chain;
+    // keeping the rest of the chain short makes it less likely that other toggles also lead there
+    let position = chain
+        .len()
+        .saturating_sub(2 + rng.below(3))
+        .min(chain.len() - 1);
+    let block = blocks[chain[position]];
+    let candidates: Vec<usize> = (block.start..=block.end)
+        .filter(|&index| instructions[index].toggle().is_some())
+        .collect();
+    if candidates.is_empty() {
+        return None;
+    }
+    let index = candidates[rng.below(candidates.len())];
+    let target = blocks[chain[rng.below(position + 1)]].start;
+    instructions[index] = match instructions[index] {
+        Instruction::JMP(argument) => Instruction::NOP(argument),
+        Instruction::NOP(_argument) => Instruction::JMP(target as i64 - index as i64),
+        _ => return None,
+    };
+    let corrupted_index = index;
+    let mut program = Program::new(instructions);
+
+    let mut interpreter = Interpreter::new(program.clone());
+    interpreter.keep_history(); // for the path it takes
+    let part1 = match interpreter.run(None) {
+        StepOutcome::InfiniteLoop(accumulator) => accumulator,
+        _ => return None,
+    };
+    // a NOP on the loop whose toggled jump leads to termination would be another repair,
+    // so point it at the loop instead (NOP arguments change neither the loop nor termination)
+    let terminating = repair::accumulator_to_end(&program);
+    let path: Vec<usize> = interpreter
+        .trace()
+        .iter()
+        .map(|entry| entry.index)
+        .collect();
+    for &index in &path {
+        if let Instruction::NOP(argument) = program.instructions[index] {
+            let target = index as i64 + argument;
+            if index != corrupted_index
+                && target >= 0
+                && target <= len as i64
+                && terminating[target as usize].is_some()
+            {
+                let target = path[rng.below(path.len())];
+                program.instructions[index] = Instruction::NOP(target as i64 - index as i64);
+            }
+        }
+    }
+    let repairs = repair::repair_minimal(
+        &program,
+        RepairOptions {
+            max_edits: 1,
+            swap_acc: false,
+            edit_acc: false,
+        },
+    );
+    // the only repair has to undo the corruption, not toggle some other instruction
+    if !repairs.unique
+        || repairs.repairs[0].edits.len() != 1
+        || repairs.repairs[0].edits[0].index != corrupted_index
+    {
+        return None;
+    }
+    Some(Generated {
+        corrupted_index,
+        part2: repairs.repairs[0].accumulator,
+        program,
+        part1,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{part1, part2, part2_brute_force};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_generate_is_reproducible() {
+        let options = GeneratorOptions::default();
+        assert_eq!(generate(42, &options), generate(42, &options));
+        assert_ne!(generate(42, &options), generate(43, &options));
+    }
+
+    #[test]
+    fn test_generate_matches_solvers() {
+        for &(len, acc_weight, jmp_weight, nop_weight) in
+            &[(1, 1, 1, 1), (20, 9, 5, 2), (200, 9, 5, 2), (200, 1, 1, 1)]
+        {
+            let options = GeneratorOptions {
+                len,
+                acc_weight,
+                jmp_weight,
+                nop_weight,
+            };
+            for seed in 0..20 {
+                let generated = generate(seed, &options).expect("generated program");
+                assert_eq!(len, generated.program.instructions.len());
+                assert_eq!(
+                    Some(generated.corrupted_index),
+                    repair::repair(&generated.program).map(|repair| repair.index)
+                );
+                assert_eq!(generated.part1, part1(generated.program.clone()));
+                assert_eq!(generated.part2, part2(generated.program.clone()));
+                assert_eq!(
+                    generated.part2,
+                    part2_brute_force(generated.program.clone())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_generate_impossible() {
+        let options = GeneratorOptions {
+            len: 10,
+            acc_weight: 0,
+            jmp_weight: 0,
+            nop_weight: 0,
+        };
+        assert_eq!(None, generate(0, &options));
+    }
+}
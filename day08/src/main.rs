@@ -1,15 +1,38 @@
-use std::collections::HashSet;
-use std::convert::TryInto;
+mod assembler;
+mod debugger;
+mod generator;
+mod observer;
+mod optimizer;
+mod profiler;
+mod repair;
+
+use debugger::Debugger;
+use generator::GeneratorOptions;
+use observer::{LoopDetector, Observer, StateLoopDetector, StepLimit, Tracer};
+use profiler::Profile;
+use repair::RepairOptions;
+use std::collections::BTreeSet;
+use std::env;
 use std::error::Error;
 use std::fmt;
 use std::fs;
+use std::io;
+use std::mem;
 use std::num::ParseIntError;
 use std::str::FromStr;
+use std::time::Instant;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 enum ParseError {
     UnknownOperation(String),
+    NotInDialect(String, Dialect),
+    WrongArgumentCount(String),
+    UnknownRegister(String),
     BadArgument(ParseIntError),
+    BadLabel(String),
+    DuplicateLabel(String),
+    UnknownLabel(String),
+    UnknownDialect(String),
 }
 
 impl fmt::Display for ParseError {
@@ -26,19 +49,179 @@ impl From<ParseIntError> for ParseError {
     }
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+// a parse error in a program, with the 1-based line and column (in characters) of the offending text
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Diagnostic {
+    line: usize,
+    column: usize,
+    text: String,
+    error: ParseError,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {} (`{}`)",
+            self.line, self.column, self.error, self.text
+        )
+    }
+}
+
+// all the parse errors in a program, in the order they appear
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Diagnostics(Vec<Diagnostic>);
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, diagnostic) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Diagnostics {}
+
+// versions of the instruction set, oldest first; each one includes all the previous ones
+#[derive(Copy, Clone, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+enum Dialect {
+    #[default]
+    Aoc2020, // acc, jmp, nop
+    Extended, // adds registers r1-r3, add, mul, jz, jnz, out, hlt
+}
+
+impl Dialect {
+    fn of_operation(operation: &str) -> Option<Dialect> {
+        match operation {
+            "acc" | "jmp" | "nop" => Some(Dialect::Aoc2020),
+            "add" | "mul" | "jz" | "jnz" | "out" | "hlt" => Some(Dialect::Extended),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Dialect {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "2020" => Ok(Dialect::Aoc2020),
+            "extended" => Ok(Dialect::Extended),
+            _ => Err(ParseError::UnknownDialect(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Dialect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dialect::Aoc2020 => write!(f, "2020"),
+            Dialect::Extended => write!(f, "extended"),
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+enum Register {
+    ACC,
+    R1,
+    R2,
+    R3,
+}
+
+impl FromStr for Register {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "acc" => Ok(Register::ACC),
+            "r1" => Ok(Register::R1),
+            "r2" => Ok(Register::R2),
+            "r3" => Ok(Register::R3),
+            _ => Err(ParseError::UnknownRegister(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Register::ACC => write!(f, "acc"),
+            Register::R1 => write!(f, "r1"),
+            Register::R2 => write!(f, "r2"),
+            Register::R3 => write!(f, "r3"),
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 enum Instruction {
     ACC(i64),
     JMP(i64),
     NOP(i64),
+    ADD(Register, i64),
+    MUL(Register, i64),
+    JZ(Register, i64),
+    JNZ(Register, i64),
+    OUT(Register),
+    HLT,
 }
 
 impl Instruction {
+    // errors come with the position of the offending word: 0 for the whole instruction,
+    // otherwise the argument (so 1 for the first one)
+    fn parse_words(
+        operation: &str,
+        arguments: &[&str],
+        dialect: Dialect,
+    ) -> Result<Self, (usize, ParseError)> {
+        match Dialect::of_operation(operation) {
+            None => return Err((0, ParseError::UnknownOperation(operation.to_owned()))),
+            Some(operation_dialect) if operation_dialect > dialect => {
+                return Err((0, ParseError::NotInDialect(operation.to_owned(), dialect)))
+            }
+            Some(_) => (),
+        }
+        let number = |word: usize| -> Result<i64, (usize, ParseError)> {
+            arguments[word - 1]
+                .parse()
+                .map_err(|e: ParseIntError| (word, e.into()))
+        };
+        let register = |word: usize| -> Result<Register, (usize, ParseError)> {
+            arguments[word - 1].parse().map_err(|e| (word, e))
+        };
+        match (operation, arguments.len()) {
+            ("acc", 1) => Ok(Instruction::ACC(number(1)?)),
+            ("jmp", 1) => Ok(Instruction::JMP(number(1)?)),
+            ("nop", 1) => Ok(Instruction::NOP(number(1)?)),
+            ("add", 2) => Ok(Instruction::ADD(register(1)?, number(2)?)),
+            ("mul", 2) => Ok(Instruction::MUL(register(1)?, number(2)?)),
+            ("jz", 2) => Ok(Instruction::JZ(register(1)?, number(2)?)),
+            ("jnz", 2) => Ok(Instruction::JNZ(register(1)?, number(2)?)),
+            ("out", 1) => Ok(Instruction::OUT(register(1)?)),
+            ("hlt", 0) => Ok(Instruction::HLT),
+            _ => Err((0, ParseError::WrongArgumentCount(operation.to_owned()))),
+        }
+    }
+
+    fn dialect(self) -> Dialect {
+        match self {
+            Instruction::ACC(_) | Instruction::JMP(_) | Instruction::NOP(_) => Dialect::Aoc2020,
+            _ => Dialect::Extended,
+        }
+    }
+
     fn toggle(self) -> Option<Instruction> {
         match self {
-            Instruction::ACC(_argument) => None,
             Instruction::JMP(argument) => Some(Instruction::NOP(argument)),
             Instruction::NOP(argument) => Some(Instruction::JMP(argument)),
+            _ => None,
         }
     }
 }
@@ -47,14 +230,29 @@ impl FromStr for Instruction {
     type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (operation, argument) = s.split_at(3);
-        let argument = &argument[1..];
-        let argument: i64 = argument.parse()?;
-        match operation {
-            "acc" => Ok(Instruction::ACC(argument)),
-            "jmp" => Ok(Instruction::JMP(argument)),
-            "nop" => Ok(Instruction::NOP(argument)),
-            _ => Err(ParseError::UnknownOperation(operation.to_owned())),
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words.split_first() {
+            Some((operation, arguments)) => {
+                Instruction::parse_words(operation, arguments, Dialect::default())
+                    .map_err(|(_word, e)| e)
+            }
+            None => Err(ParseError::UnknownOperation(String::new())),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::ACC(argument) => write!(f, "acc {:+}", argument),
+            Instruction::JMP(argument) => write!(f, "jmp {:+}", argument),
+            Instruction::NOP(argument) => write!(f, "nop {:+}", argument),
+            Instruction::ADD(register, argument) => write!(f, "add {} {:+}", register, argument),
+            Instruction::MUL(register, argument) => write!(f, "mul {} {:+}", register, argument),
+            Instruction::JZ(register, argument) => write!(f, "jz {} {:+}", register, argument),
+            Instruction::JNZ(register, argument) => write!(f, "jnz {} {:+}", register, argument),
+            Instruction::OUT(register) => write!(f, "out {}", register),
+            Instruction::HLT => write!(f, "hlt"),
         }
     }
 }
@@ -62,24 +260,91 @@ impl FromStr for Instruction {
 #[derive(Clone, Debug, Eq, PartialEq)]
 struct Program {
     instructions: Vec<Instruction>,
+    dialect: Dialect, // the one it was written in, which decides how loops are detected
+}
+
+impl Program {
+    // in the oldest dialect that has all the instructions
+    fn new(instructions: Vec<Instruction>) -> Self {
+        let mut program = Program {
+            instructions,
+            dialect: Dialect::default(),
+        };
+        program.dialect = program.oldest_dialect();
+        program
+    }
+
+    fn parse_dialect(s: &str, dialect: Dialect) -> Result<Self, Diagnostics> {
+        assembler::assemble(s, dialect)
+    }
+
+    // the oldest dialect that has all the instructions of this program,
+    // which can be older than the one it was written in
+    fn oldest_dialect(&self) -> Dialect {
+        self.instructions
+            .iter()
+            .map(|instruction| instruction.dialect())
+            .max()
+            .unwrap_or_default()
+    }
 }
 
 impl FromStr for Program {
-    type Err = ParseError;
+    type Err = Diagnostics;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let instructions: Result<Vec<_>, _> = s.lines().map(|s| s.parse::<Instruction>()).collect();
-        Ok(Program {
-            instructions: instructions?,
-        })
+        Program::parse_dialect(s, Dialect::default())
+    }
+}
+
+// {:#} prints the program with labels instead of relative jumps;
+// any dialect but the default one comes first, as a directive that parsing reads back
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.dialect != Dialect::default() {
+            writeln!(f, ".dialect {}", self.dialect)?;
+        }
+        if f.alternate() {
+            return assembler::write_labelled(self, f);
+        }
+        for instruction in &self.instructions {
+            writeln!(f, "{}", instruction)?;
+        }
+        Ok(())
     }
 }
 
 struct Interpreter {
     program: Program,
     accumulator: i64,
+    registers: [i64; 3], // r1-r3
     instruction_pointer: usize,
-    seen_instructions: HashSet<usize>,
+    output: Vec<i64>,
+    steps: usize,                     // executed and not stepped back
+    history: Option<Vec<TraceEntry>>, // only if kept, see keep_history
+    fault_policy: FaultPolicy,
+    observers: Vec<Box<dyn Observer>>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct TraceEntry {
+    index: usize,
+    instruction: Instruction,
+    accumulator_before: i64,
+    accumulator_after: i64,
+    registers_before: [i64; 3],
+}
+
+impl TraceEntry {
+    const HEADER: &'static str =
+        "step\tindex\tinstruction\taccumulator_before\taccumulator_after\n";
+
+    fn row(&self, step: usize) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            step, self.index, self.instruction, self.accumulator_before, self.accumulator_after
+        )
+    }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -87,60 +352,326 @@ enum StepOutcome {
     Continue,
     InfiniteLoop(i64),
     Terminate(i64),
+    Fault(Fault),
+    StepLimit(i64), // see observer::StepLimit
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Fault {
+    JumpBelowZero,
+    JumpPastEnd, // jumping exactly to the end terminates the program, anything further is a fault
+    AccumulatorOverflow, // also used for the other registers
+}
+
+// what to do when an instruction would fault
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum FaultPolicy {
+    Trap,  // stop with StepOutcome::Fault, without executing the instruction
+    Wrap,  // wrap jumps around the program length, and registers around i64
+    Clamp, // clamp jumps to the start or end of the program, and saturate registers
+}
+
+impl FromStr for FaultPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trap" => Ok(FaultPolicy::Trap),
+            "wrap" => Ok(FaultPolicy::Wrap),
+            "clamp" => Ok(FaultPolicy::Clamp),
+            _ => Err(format!("unknown fault policy: {}", s)),
+        }
+    }
+}
+
+// the changes made by executing one instruction
+struct Effect {
+    register: Option<(Register, i64)>,
+    instruction_pointer: usize,
+    output: Option<i64>,
 }
 
 impl Interpreter {
     fn new(program: Program) -> Self {
+        Interpreter::with_fault_policy(program, FaultPolicy::Trap)
+    }
+
+    // loops in the 2020 dialect are detected by instruction;
+    // with conditional jumps, only a repeated state (instruction and registers) is a loop
+    fn with_fault_policy(program: Program, fault_policy: FaultPolicy) -> Self {
+        let loop_detector: Box<dyn Observer> = match program.dialect {
+            Dialect::Aoc2020 => Box::new(LoopDetector::default()),
+            Dialect::Extended => Box::new(StateLoopDetector::default()),
+        };
+        Interpreter::with_observers(program, fault_policy, vec![loop_detector])
+    }
+
+    fn with_observers(
+        program: Program,
+        fault_policy: FaultPolicy,
+        observers: Vec<Box<dyn Observer>>,
+    ) -> Self {
         Interpreter {
             program,
             accumulator: 0,
+            registers: [0; 3],
             instruction_pointer: 0,
-            seen_instructions: HashSet::new(),
+            output: Vec::new(),
+            steps: 0,
+            history: None,
+            fault_policy,
+            observers,
         }
     }
 
-    fn step(&mut self) -> StepOutcome {
-        if self.seen_instructions.contains(&self.instruction_pointer) {
-            return StepOutcome::InfiniteLoop(self.accumulator);
+    fn add_observer(&mut self, observer: Box<dyn Observer>) {
+        self.observers.push(observer);
+    }
+
+    /// Keep a history of every step from now on, which stepping back and rewinding need;
+    /// without one, a long run doesn't grow the interpreter.
+    fn keep_history(&mut self) {
+        self.history.get_or_insert_with(Vec::new);
+    }
+
+    fn register(&self, register: Register) -> i64 {
+        match register {
+            Register::ACC => self.accumulator,
+            _ => self.registers[register as usize - 1],
+        }
+    }
+
+    fn set_register(&mut self, register: Register, value: i64) {
+        match register {
+            Register::ACC => self.accumulator = value,
+            _ => self.registers[register as usize - 1] = value,
         }
-        self.seen_instructions.insert(self.instruction_pointer);
+    }
+
+    fn state(&self) -> (usize, i64, [i64; 3]) {
+        (self.instruction_pointer, self.accumulator, self.registers)
+    }
+
+    fn step(&mut self) -> StepOutcome {
         let instruction = match self.program.instructions.get(self.instruction_pointer) {
-            Some(instruction) => instruction,
+            Some(&instruction) => instruction,
             None => return StepOutcome::Terminate(self.accumulator),
         };
+        // the observers are taken out while they run, so that they can look at the interpreter
+        let mut observers = mem::take(&mut self.observers);
+        let stopped = observers
+            .iter_mut()
+            .find_map(|observer| observer.before_step(self));
+        let outcome = match stopped {
+            Some(outcome) => outcome,
+            None => match self.execute(instruction) {
+                Ok(effect) => {
+                    let entry = self.apply(instruction, effect);
+                    observers
+                        .iter_mut()
+                        .fold(None, |stopped, observer| {
+                            // every observer sees the step, even after one of them stopped
+                            let outcome = observer.after_step(self, &entry);
+                            stopped.or(outcome)
+                        })
+                        .unwrap_or(StepOutcome::Continue)
+                }
+                Err(fault) => StepOutcome::Fault(fault),
+            },
+        };
+        self.observers = observers;
+        outcome
+    }
+
+    /// Step until the program stops, or until the budget of steps (if any) runs out,
+    /// in which case the result is StepOutcome::Continue.
+    fn run(&mut self, budget: Option<usize>) -> StepOutcome {
+        let mut steps = 0;
+        loop {
+            if budget == Some(steps) {
+                return StepOutcome::Continue;
+            }
+            match self.step() {
+                StepOutcome::Continue => steps += 1,
+                outcome => return outcome,
+            }
+        }
+    }
+
+    fn apply(&mut self, instruction: Instruction, effect: Effect) -> TraceEntry {
+        let entry_index = self.instruction_pointer;
+        let accumulator_before = self.accumulator;
+        let registers_before = self.registers;
+        if let Some((register, value)) = effect.register {
+            self.set_register(register, value);
+        }
+        self.output.extend(effect.output);
+        self.instruction_pointer = effect.instruction_pointer;
+        let entry = TraceEntry {
+            index: entry_index,
+            instruction,
+            accumulator_before,
+            accumulator_after: self.accumulator,
+            registers_before,
+        };
+        self.steps += 1;
+        if let Some(history) = &mut self.history {
+            history.push(entry);
+        }
+        entry
+    }
+
+    fn execute(&self, instruction: Instruction) -> Result<Effect, Fault> {
+        let next = self.instruction_pointer + 1;
+        let effect = |register, instruction_pointer| Effect {
+            register,
+            instruction_pointer,
+            output: None,
+        };
         match instruction {
             Instruction::ACC(argument) => {
-                self.accumulator += argument;
-                self.instruction_pointer += 1;
+                let value = self.add(self.accumulator, argument)?;
+                Ok(effect(Some((Register::ACC, value)), next))
+            }
+            Instruction::JMP(argument) => Ok(effect(None, self.jump_target(argument)?)),
+            Instruction::NOP(_argument) => Ok(effect(None, next)),
+            Instruction::ADD(register, argument) => {
+                let value = self.add(self.register(register), argument)?;
+                Ok(effect(Some((register, value)), next))
+            }
+            Instruction::MUL(register, argument) => {
+                let value = self.register(register);
+                let value = match value.checked_mul(argument) {
+                    Some(value) => value,
+                    None => match self.fault_policy {
+                        FaultPolicy::Trap => return Err(Fault::AccumulatorOverflow),
+                        FaultPolicy::Wrap => value.wrapping_mul(argument),
+                        FaultPolicy::Clamp => value.saturating_mul(argument),
+                    },
+                };
+                Ok(effect(Some((register, value)), next))
+            }
+            Instruction::JZ(register, argument) if self.register(register) == 0 => {
+                Ok(effect(None, self.jump_target(argument)?))
             }
-            Instruction::JMP(argument) => {
-                self.instruction_pointer = ((self.instruction_pointer as i64) + argument)
-                    .try_into()
-                    .expect("JMP out of bounds");
+            Instruction::JNZ(register, argument) if self.register(register) != 0 => {
+                Ok(effect(None, self.jump_target(argument)?))
             }
-            Instruction::NOP(_argument) => {
-                self.instruction_pointer += 1;
+            Instruction::JZ(_register, _argument) | Instruction::JNZ(_register, _argument) => {
+                Ok(effect(None, next))
             }
+            Instruction::OUT(register) => Ok(Effect {
+                register: None,
+                instruction_pointer: next,
+                output: Some(self.register(register)),
+            }),
+            Instruction::HLT => Ok(effect(None, self.program.instructions.len())),
+        }
+    }
+
+    fn add(&self, value: i64, argument: i64) -> Result<i64, Fault> {
+        match value.checked_add(argument) {
+            Some(value) => Ok(value),
+            None => match self.fault_policy {
+                FaultPolicy::Trap => Err(Fault::AccumulatorOverflow),
+                FaultPolicy::Wrap => Ok(value.wrapping_add(argument)),
+                FaultPolicy::Clamp => Ok(value.saturating_add(argument)),
+            },
+        }
+    }
+
+    fn jump_target(&self, argument: i64) -> Result<usize, Fault> {
+        let len = self.program.instructions.len() as i64;
+        let target = (self.instruction_pointer as i64).saturating_add(argument);
+        let fault = if target < 0 {
+            Fault::JumpBelowZero
+        } else if target > len {
+            Fault::JumpPastEnd
+        } else {
+            return Ok(target as usize);
         };
-        StepOutcome::Continue
+        match self.fault_policy {
+            FaultPolicy::Trap => Err(fault),
+            FaultPolicy::Wrap => Ok(target.rem_euclid(len) as usize),
+            FaultPolicy::Clamp => Ok(target.clamp(0, len) as usize),
+        }
+    }
+
+    // None at the start of the history, or without one
+    fn step_back(&mut self) -> Option<TraceEntry> {
+        let entry = self.history.as_mut()?.pop()?;
+        self.steps -= 1;
+        self.instruction_pointer = entry.index;
+        self.accumulator = entry.accumulator_before;
+        self.registers = entry.registers_before;
+        if let Instruction::OUT(_register) = entry.instruction {
+            self.output.pop();
+        }
+        let mut observers = mem::take(&mut self.observers);
+        for observer in &mut observers {
+            observer.step_back(self, &entry);
+        }
+        self.observers = observers;
+        Some(entry)
+    }
+
+    // go back to the state before the given step (0 = the initial state),
+    // unless the history doesn't reach back that far, which returns false
+    fn rewind(&mut self, step: usize) -> bool {
+        if self.steps.saturating_sub(step) > self.trace().len() {
+            return false;
+        }
+        while self.steps > step {
+            self.step_back();
+        }
+        true
+    }
+
+    fn steps(&self) -> usize {
+        self.steps
+    }
+
+    // the steps in the history, which is empty if none is kept
+    fn trace(&self) -> &[TraceEntry] {
+        self.history.as_deref().unwrap_or(&[])
+    }
+
+    // the instructions executed in the history
+    fn seen_instructions(&self) -> BTreeSet<usize> {
+        self.trace().iter().map(|entry| entry.index).collect()
+    }
+
+    fn trace_table(&self) -> String {
+        let mut table = String::from(TraceEntry::HEADER);
+        // the history may have been started after the first step
+        let first_step = self.steps - self.trace().len();
+        for (step, entry) in self.trace().iter().enumerate() {
+            table += &entry.row(first_step + step);
+        }
+        table
     }
 }
 
 fn part1(program: Program) -> i64 {
-    let mut interpreter = Interpreter::new(program);
-    loop {
-        match interpreter.step() {
-            StepOutcome::Continue => (),
-            StepOutcome::InfiniteLoop(accumulator) => break accumulator,
-            StepOutcome::Terminate(accumulator) => break accumulator,
-        }
+    match Interpreter::new(program).run(None) {
+        StepOutcome::InfiniteLoop(accumulator) => accumulator,
+        StepOutcome::Terminate(accumulator) => accumulator,
+        outcome => panic!("{:?}", outcome),
     }
 }
 
 fn part2(program: Program) -> i64 {
+    repair::repair(&program)
+        .expect("No solution found!")
+        .accumulator
+}
+
+#[cfg(test)]
+fn part2_brute_force(program: Program) -> i64 {
     for index in 0..program.instructions.len() {
         let mut toggled_program = Program {
-            instructions: program.instructions.clone()
+            instructions: program.instructions.clone(),
+            dialect: program.dialect
         };
         if let Some(toggled_instruction) = toggled_program.instructions[index].toggle() {
             toggled_program.instructions[index] = toggled_instruction;
@@ -150,21 +681,119 @@ fn part2(program: Program) -> i64 {
 
 
         let mut interpreter = Interpreter::new(toggled_program);
-        loop {
-            match interpreter.step() {
-                StepOutcome::Continue => (),
-                StepOutcome::InfiniteLoop(_accumulator) => break,
-                StepOutcome::Terminate(accumulator) => return accumulator,
-            }
+        if let StepOutcome::Terminate(accumulator) = interpreter.run(None) {
+            return accumulator;
         }
     }
     panic!("No solution found!");
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let program: Program = fs::read_to_string("input")?.parse()?;
-    println!("{}", part1(program.clone()));
-    println!("{}", part2(program.clone()));
+    let (flags, args): (Vec<String>, Vec<String>) =
+        env::args().skip(1).partition(|arg| arg.starts_with("--"));
+    let has_flag = |flag: &str| flags.iter().any(|arg| arg == flag);
+    let dialect = if has_flag("--extended") {
+        Dialect::Extended
+    } else {
+        Dialect::Aoc2020
+    };
+    if args.first().map(String::as_str) == Some("generate") {
+        let seed = match args.get(1) {
+            Some(seed) => seed.parse()?,
+            None => 0,
+        };
+        let mut options = GeneratorOptions::default();
+        if let Some(len) = args.get(2) {
+            options.len = len.parse()?;
+        }
+        let generated = generator::generate(seed, &options).ok_or("no program generated")?;
+        println!("# part 1: {}", generated.part1);
+        println!("# part 2: {}", generated.part2);
+        println!("# corrupted instruction: {}", generated.corrupted_index);
+        print!("{}", generated.program);
+        return Ok(());
+    }
+    let program = match Program::parse_dialect(&fs::read_to_string("input")?, dialect) {
+        Ok(program) => program,
+        Err(Diagnostics(diagnostics)) => {
+            for diagnostic in &diagnostics {
+                eprintln!("input:{}", diagnostic);
+            }
+            return Err(format!("{} errors in input", diagnostics.len()).into());
+        }
+    };
+    match args.first().map(String::as_str) {
+        Some("debug") => {
+            let stdin = io::stdin();
+            Debugger::new(Interpreter::new(program), stdin.lock(), io::stdout()).run()?;
+        }
+        Some("run") => {
+            let mut interpreter = Interpreter::new(program);
+            if let Some(max_steps) = args.get(1) {
+                interpreter.add_observer(Box::new(StepLimit::new(max_steps.parse()?)));
+            }
+            if has_flag("--trace") {
+                interpreter.add_observer(Box::new(Tracer::new(io::stderr())));
+            }
+            let outcome = interpreter.run(None);
+            for value in &interpreter.output {
+                println!("{}", value);
+            }
+            println!("{:?}", outcome);
+        }
+        Some("profile") => {
+            let (outcome, profile) = Profile::run(&mut Interpreter::new(program.clone()));
+            if has_flag("--csv") {
+                print!("{}", profile.csv(&program));
+            } else {
+                print!("{}", profile.listing(&program));
+                println!("{:?}", outcome);
+            }
+        }
+        Some("optimize") => {
+            let start = Instant::now();
+            let (optimized, stats) = optimizer::optimize(&program);
+            let elapsed = start.elapsed();
+            let budget = optimizer::EQUIVALENCE_BUDGET;
+            let equivalent = optimizer::equivalent(&program, &optimized, budget);
+            if equivalent == Some(false) {
+                return Err("optimized program behaves differently".into());
+            }
+            print!("{}", optimized);
+            eprintln!("{}", stats);
+            match equivalent {
+                Some(_) => eprintln!("optimized in {:?}, equivalence checked", elapsed),
+                None => eprintln!(
+                    "optimized in {:?}, equivalence undecided: no outcome within {} steps",
+                    elapsed, budget
+                ),
+            }
+        }
+        Some("repair") => {
+            let options = RepairOptions {
+                max_edits: match args.get(1) {
+                    Some(max_edits) => max_edits.parse()?,
+                    None => 1,
+                },
+                swap_acc: has_flag("--swap-acc"),
+                edit_acc: has_flag("--edit-acc"),
+            };
+            let repairs = repair::repair_minimal(&program, options);
+            for repair in &repairs.repairs {
+                let edits: Vec<String> = repair
+                    .edits
+                    .iter()
+                    .map(|edit| format!("{}: {}", edit.index, edit.instruction))
+                    .collect();
+                println!("{} => {}", edits.join(", "), repair.accumulator);
+            }
+            println!("unique: {}", repairs.unique);
+        }
+        _ => {
+            println!("{}", part1(program.clone()));
+            println!("{}", part2(program.clone()));
+        }
+    }
     Ok(())
 }
 
@@ -205,12 +834,7 @@ mod tests {
 
     #[test]
     fn test_parse_program() {
-        assert_eq!(
-            "".parse::<Program>().unwrap(),
-            Program {
-                instructions: vec![]
-            }
-        );
+        assert_eq!("".parse::<Program>().unwrap(), Program::new(vec![]));
         assert_eq!(
             "
 nop +0
@@ -226,26 +850,7 @@ acc +6
             .trim()
             .parse::<Program>()
             .unwrap(),
-            Program {
-                instructions: vec![
-                    Instruction::NOP(0),
-                    Instruction::ACC(1),
-                    Instruction::JMP(4),
-                    Instruction::ACC(3),
-                    Instruction::JMP(-3),
-                    Instruction::ACC(-99),
-                    Instruction::ACC(1),
-                    Instruction::JMP(-4),
-                    Instruction::ACC(6),
-                ]
-            }
-        );
-    }
-
-    #[test]
-    fn test_step_program() {
-        let mut interpreter = Interpreter::new(Program {
-            instructions: vec![
+            Program::new(vec![
                 Instruction::NOP(0),
                 Instruction::ACC(1),
                 Instruction::JMP(4),
@@ -255,8 +860,23 @@ acc +6
                 Instruction::ACC(1),
                 Instruction::JMP(-4),
                 Instruction::ACC(6),
-            ],
-        });
+            ])
+        );
+    }
+
+    #[test]
+    fn test_step_program() {
+        let mut interpreter = Interpreter::new(Program::new(vec![
+            Instruction::NOP(0),
+            Instruction::ACC(1),
+            Instruction::JMP(4),
+            Instruction::ACC(3),
+            Instruction::JMP(-3),
+            Instruction::ACC(-99),
+            Instruction::ACC(1),
+            Instruction::JMP(-4),
+            Instruction::ACC(6),
+        ]));
         assert_eq!(0, interpreter.accumulator);
         assert_eq!(0, interpreter.instruction_pointer);
 
@@ -292,40 +912,238 @@ acc +6
     }
 
     #[test]
-    fn test_part1() {
-        let program = Program {
-            instructions: vec![
-                Instruction::NOP(0),
-                Instruction::ACC(1),
-                Instruction::JMP(4),
-                Instruction::ACC(3),
-                Instruction::JMP(-3),
-                Instruction::ACC(-99),
-                Instruction::ACC(1),
-                Instruction::JMP(-4),
-                Instruction::ACC(6),
-            ],
+    fn test_faults() {
+        let program = Program::new(vec![
+            Instruction::NOP(0),
+            Instruction::JMP(-2),
+            Instruction::JMP(4),
+            Instruction::ACC(i64::MAX),
+            Instruction::ACC(1),
+        ]);
+
+        let mut interpreter = Interpreter::new(program.clone());
+        interpreter.instruction_pointer = 1;
+        assert_eq!(StepOutcome::Fault(Fault::JumpBelowZero), interpreter.step());
+        assert_eq!(1, interpreter.instruction_pointer);
+        interpreter.instruction_pointer = 2;
+        assert_eq!(StepOutcome::Fault(Fault::JumpPastEnd), interpreter.step());
+        assert_eq!(2, interpreter.instruction_pointer);
+        interpreter.instruction_pointer = 3;
+        assert_eq!(StepOutcome::Continue, interpreter.step());
+        assert_eq!(
+            StepOutcome::Fault(Fault::AccumulatorOverflow),
+            interpreter.step()
+        );
+        assert_eq!(i64::MAX, interpreter.accumulator);
+        assert_eq!(4, interpreter.instruction_pointer);
+
+        let mut interpreter = Interpreter::with_fault_policy(program.clone(), FaultPolicy::Wrap);
+        interpreter.instruction_pointer = 1;
+        assert_eq!(StepOutcome::Continue, interpreter.step());
+        assert_eq!(4, interpreter.instruction_pointer);
+        interpreter.accumulator = i64::MAX;
+        assert_eq!(StepOutcome::Continue, interpreter.step());
+        assert_eq!(i64::MIN, interpreter.accumulator);
+        interpreter.instruction_pointer = 2;
+        assert_eq!(StepOutcome::Continue, interpreter.step());
+        assert_eq!(1, interpreter.instruction_pointer);
+
+        let mut interpreter = Interpreter::with_fault_policy(program, FaultPolicy::Clamp);
+        interpreter.instruction_pointer = 1;
+        assert_eq!(StepOutcome::Continue, interpreter.step());
+        assert_eq!(0, interpreter.instruction_pointer);
+        interpreter.instruction_pointer = 3;
+        assert_eq!(StepOutcome::Continue, interpreter.step());
+        assert_eq!(StepOutcome::Continue, interpreter.step());
+        assert_eq!(i64::MAX, interpreter.accumulator);
+        interpreter.instruction_pointer = 2;
+        assert_eq!(StepOutcome::Continue, interpreter.step());
+        assert_eq!(StepOutcome::Terminate(i64::MAX), interpreter.step());
+    }
+
+    #[test]
+    fn test_extended_dialect() {
+        let program = Program::parse_dialect(
+            "
+            add r1 +3
+            loop: mul acc +2
+            add acc +1
+            out acc
+            add r1 -1
+            jnz r1 loop
+            hlt
+            out acc
+            ",
+            Dialect::Extended,
+        )
+        .unwrap();
+        let mut interpreter = Interpreter::new(program);
+        interpreter.keep_history();
+        let outcome = loop {
+            match interpreter.step() {
+                StepOutcome::Continue => (),
+                outcome => break outcome,
+            }
         };
+        // revisiting instructions with different registers is not a loop
+        assert_eq!(StepOutcome::Terminate(7), outcome);
+        assert_eq!(vec![1, 3, 7], interpreter.output);
+        assert_eq!(0, interpreter.register(Register::R1));
+
+        assert!(interpreter.rewind(9));
+        assert_eq!(vec![1, 3], interpreter.output);
+        assert_eq!(2, interpreter.register(Register::R1));
+        assert_eq!(3, interpreter.accumulator);
+        assert_eq!(4, interpreter.instruction_pointer);
+
+        let mut interpreter =
+            Interpreter::new(Program::parse_dialect("jz r2 +0", Dialect::Extended).unwrap());
+        assert_eq!(StepOutcome::Continue, interpreter.step());
+        assert_eq!(StepOutcome::InfiniteLoop(0), interpreter.step());
+
+        // the dialect the program was written in decides, not the instructions it happens to use
+        let source = "acc +4611686018427387904\njmp -1";
+        let program = Program::parse_dialect(source, Dialect::Aoc2020).unwrap();
+        assert_eq!(
+            StepOutcome::InfiniteLoop(1 << 62),
+            Interpreter::new(program).run(None)
+        );
+        let program = Program::parse_dialect(source, Dialect::Extended).unwrap();
+        assert_eq!(Dialect::Aoc2020, program.oldest_dialect());
+        assert_eq!(
+            StepOutcome::Fault(Fault::AccumulatorOverflow),
+            Interpreter::new(program).run(None)
+        );
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut interpreter = Interpreter::new(Program::new(vec![
+            Instruction::NOP(0),
+            Instruction::ACC(1),
+            Instruction::JMP(4),
+            Instruction::ACC(3),
+            Instruction::JMP(-3),
+            Instruction::ACC(-99),
+            Instruction::ACC(1),
+            Instruction::JMP(-4),
+            Instruction::ACC(6),
+        ]));
+        interpreter.keep_history();
+        while interpreter.step() == StepOutcome::Continue {}
+        assert_eq!(7, interpreter.trace().len());
+        assert_eq!(
+            "\
+step\tindex\tinstruction\taccumulator_before\taccumulator_after
+0\t0\tnop +0\t0\t0
+1\t1\tacc +1\t0\t1
+2\t2\tjmp +4\t1\t1
+3\t6\tacc +1\t1\t2
+4\t7\tjmp -4\t2\t2
+5\t3\tacc +3\t2\t5
+6\t4\tjmp -3\t5\t5
+",
+            interpreter.trace_table()
+        );
+
+        assert_eq!(
+            Some(TraceEntry {
+                index: 4,
+                instruction: Instruction::JMP(-3),
+                accumulator_before: 5,
+                accumulator_after: 5,
+                registers_before: [0; 3],
+            }),
+            interpreter.step_back()
+        );
+        assert_eq!(5, interpreter.accumulator);
+        assert_eq!(4, interpreter.instruction_pointer);
+        assert!(!interpreter.seen_instructions().contains(&4));
+
+        assert!(interpreter.rewind(3));
+        assert_eq!(1, interpreter.accumulator);
+        assert_eq!(6, interpreter.instruction_pointer);
+        assert_eq!(3, interpreter.trace().len());
+
+        assert!(interpreter.rewind(0));
+        assert_eq!(0, interpreter.accumulator);
+        assert_eq!(0, interpreter.instruction_pointer);
+        assert!(interpreter.seen_instructions().is_empty());
+        assert_eq!(None, interpreter.step_back());
+
+        // replaying after rewinding ends in the same loop
+        while interpreter.step() == StepOutcome::Continue {}
+        assert_eq!(StepOutcome::InfiniteLoop(5), interpreter.step());
+    }
+
+    #[test]
+    fn test_history_is_opt_in() {
+        let program: Program = "acc +1\nacc +2\nacc +3\njmp -3".parse().unwrap();
+        let mut interpreter = Interpreter::new(program.clone());
+        assert_eq!(StepOutcome::InfiniteLoop(6), interpreter.run(None));
+        assert_eq!(4, interpreter.steps());
+        assert!(interpreter.trace().is_empty());
+        assert_eq!(None, interpreter.step_back());
+        assert!(!interpreter.rewind(2));
+        assert_eq!(
+            (0, 6),
+            (interpreter.instruction_pointer, interpreter.accumulator)
+        );
+
+        // a history started late only reaches back to where it started
+        let mut interpreter = Interpreter::new(program);
+        assert_eq!(StepOutcome::Continue, interpreter.run(Some(2)));
+        interpreter.keep_history();
+        assert_eq!(StepOutcome::Continue, interpreter.run(Some(2)));
+        assert_eq!(
+            "\
+step\tindex\tinstruction\taccumulator_before\taccumulator_after
+2\t2\tacc +3\t3\t6
+3\t3\tjmp -3\t6\t6
+",
+            interpreter.trace_table()
+        );
+        assert!(!interpreter.rewind(1));
+        assert!(interpreter.rewind(2));
+        assert_eq!(
+            (2, 3),
+            (interpreter.instruction_pointer, interpreter.accumulator)
+        );
+        assert_eq!(None, interpreter.step_back());
+    }
+
+    #[test]
+    fn test_part1() {
+        let program = Program::new(vec![
+            Instruction::NOP(0),
+            Instruction::ACC(1),
+            Instruction::JMP(4),
+            Instruction::ACC(3),
+            Instruction::JMP(-3),
+            Instruction::ACC(-99),
+            Instruction::ACC(1),
+            Instruction::JMP(-4),
+            Instruction::ACC(6),
+        ]);
 
         assert_eq!(5, part1(program));
     }
 
     #[test]
     fn test_part2() {
-        let program = Program {
-            instructions: vec![
-                Instruction::NOP(0),
-                Instruction::ACC(1),
-                Instruction::JMP(4),
-                Instruction::ACC(3),
-                Instruction::JMP(-3),
-                Instruction::ACC(-99),
-                Instruction::ACC(1),
-                Instruction::JMP(-4),
-                Instruction::ACC(6),
-            ],
-        };
+        let program = Program::new(vec![
+            Instruction::NOP(0),
+            Instruction::ACC(1),
+            Instruction::JMP(4),
+            Instruction::ACC(3),
+            Instruction::JMP(-3),
+            Instruction::ACC(-99),
+            Instruction::ACC(1),
+            Instruction::JMP(-4),
+            Instruction::ACC(6),
+        ]);
 
-        assert_eq!(8, part2(program));
+        assert_eq!(8, part2(program.clone()));
+        assert_eq!(8, part2_brute_force(program));
     }
 }
@@ -0,0 +1,272 @@
+use crate::{Interpreter, StepOutcome, TraceEntry};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+/// Hooks into every step of an Interpreter.
+/// Returning an outcome stops the step there and reports it instead of StepOutcome::Continue:
+/// from before_step, the instruction is not executed; from after_step, it already was.
+pub trait Observer {
+    fn before_step(&mut self, _interpreter: &Interpreter) -> Option<StepOutcome> {
+        None
+    }
+
+    fn after_step(
+        &mut self,
+        _interpreter: &Interpreter,
+        _entry: &TraceEntry,
+    ) -> Option<StepOutcome> {
+        None
+    }
+
+    // called when the interpreter undoes the step of entry, so observers can undo their part too
+    fn step_back(&mut self, _interpreter: &Interpreter, _entry: &TraceEntry) {}
+}
+
+/// The puzzle's loop detection: an instruction that is about to run a second time.
+#[derive(Clone, Debug, Default)]
+pub struct LoopDetector {
+    runs: HashMap<usize, usize>, // by instruction index
+}
+
+impl Observer for LoopDetector {
+    fn before_step(&mut self, interpreter: &Interpreter) -> Option<StepOutcome> {
+        if self.runs.contains_key(&interpreter.instruction_pointer) {
+            Some(StepOutcome::InfiniteLoop(interpreter.accumulator))
+        } else {
+            None
+        }
+    }
+
+    fn after_step(
+        &mut self,
+        _interpreter: &Interpreter,
+        entry: &TraceEntry,
+    ) -> Option<StepOutcome> {
+        *self.runs.entry(entry.index).or_default() += 1;
+        None
+    }
+
+    fn step_back(&mut self, _interpreter: &Interpreter, entry: &TraceEntry) {
+        if let Some(runs) = self.runs.get_mut(&entry.index) {
+            *runs -= 1;
+            if *runs == 0 {
+                self.runs.remove(&entry.index);
+            }
+        }
+    }
+}
+
+/// Loop detection for programs whose control flow depends on registers:
+/// only the whole state (instruction pointer, accumulator and registers) repeating is a loop.
+#[derive(Clone, Debug, Default)]
+pub struct StateLoopDetector {
+    seen: HashSet<(usize, i64, [i64; 3])>,
+}
+
+impl Observer for StateLoopDetector {
+    fn before_step(&mut self, interpreter: &Interpreter) -> Option<StepOutcome> {
+        if self.seen.contains(&interpreter.state()) {
+            Some(StepOutcome::InfiniteLoop(interpreter.accumulator))
+        } else {
+            None
+        }
+    }
+
+    fn after_step(
+        &mut self,
+        _interpreter: &Interpreter,
+        entry: &TraceEntry,
+    ) -> Option<StepOutcome> {
+        self.seen.insert((
+            entry.index,
+            entry.accumulator_before,
+            entry.registers_before,
+        ));
+        None
+    }
+
+    fn step_back(&mut self, interpreter: &Interpreter, _entry: &TraceEntry) {
+        self.seen.remove(&interpreter.state());
+    }
+}
+
+/// Stops the program once it has executed the given number of steps
+/// since the limit was added (not counting steps that were stepped back).
+#[derive(Copy, Clone, Debug)]
+pub struct StepLimit {
+    pub max_steps: usize,
+    steps: usize,
+}
+
+impl StepLimit {
+    pub fn new(max_steps: usize) -> Self {
+        StepLimit {
+            max_steps,
+            steps: 0,
+        }
+    }
+}
+
+impl Observer for StepLimit {
+    fn before_step(&mut self, interpreter: &Interpreter) -> Option<StepOutcome> {
+        if self.steps >= self.max_steps {
+            Some(StepOutcome::StepLimit(interpreter.accumulator))
+        } else {
+            None
+        }
+    }
+
+    fn after_step(
+        &mut self,
+        _interpreter: &Interpreter,
+        _entry: &TraceEntry,
+    ) -> Option<StepOutcome> {
+        self.steps += 1;
+        None
+    }
+
+    fn step_back(&mut self, _interpreter: &Interpreter, _entry: &TraceEntry) {
+        // steps from before the limit was added can be stepped back too
+        self.steps = self.steps.saturating_sub(1);
+    }
+}
+
+/// Writes every executed step as a row of Interpreter::trace_table as it happens.
+pub struct Tracer<W: Write> {
+    output: W,
+    header_written: bool,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(output: W) -> Self {
+        Tracer {
+            output,
+            header_written: false,
+        }
+    }
+}
+
+impl<W: Write> Observer for Tracer<W> {
+    fn after_step(&mut self, interpreter: &Interpreter, entry: &TraceEntry) -> Option<StepOutcome> {
+        // a broken trace output shouldn't change how the program runs, so write errors are ignored
+        if !self.header_written {
+            self.header_written = true;
+            let _ = write!(self.output, "{}", TraceEntry::HEADER);
+        }
+        let _ = write!(self.output, "{}", entry.row(interpreter.steps() - 1));
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Dialect, FaultPolicy, Program};
+    use pretty_assertions::assert_eq;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    // observers live as long as the interpreter, so the test keeps another handle on the trace
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // stops before any OUT that would print a negative number
+    struct NoNegativeOutput;
+
+    impl Observer for NoNegativeOutput {
+        fn before_step(&mut self, interpreter: &Interpreter) -> Option<StepOutcome> {
+            match interpreter.program.instructions[interpreter.instruction_pointer] {
+                crate::Instruction::OUT(register) if interpreter.register(register) < 0 => {
+                    Some(StepOutcome::Terminate(interpreter.accumulator))
+                }
+                _ => None,
+            }
+        }
+    }
+
+    fn extended(source: &str) -> Program {
+        Program::parse_dialect(source, Dialect::Extended).unwrap()
+    }
+
+    #[test]
+    fn test_step_limit_and_budget() {
+        let program = extended("add r2 +0\nloop: acc +1\njmp loop");
+        let mut interpreter = Interpreter::new(program.clone());
+        assert_eq!(StepOutcome::Continue, interpreter.run(Some(10)));
+        assert_eq!(10, interpreter.steps());
+        // the limit counts from when it is added
+        interpreter.add_observer(Box::new(StepLimit::new(15)));
+        assert_eq!(StepOutcome::Continue, interpreter.run(Some(10)));
+        assert_eq!(20, interpreter.steps());
+        assert_eq!(StepOutcome::StepLimit(12), interpreter.run(None));
+        assert_eq!(25, interpreter.steps());
+
+        // and stepping back gives steps back
+        let mut interpreter = Interpreter::new(program.clone());
+        interpreter.keep_history();
+        interpreter.add_observer(Box::new(StepLimit::new(15)));
+        assert_eq!(StepOutcome::StepLimit(7), interpreter.run(None));
+        assert!(interpreter.rewind(10));
+        assert_eq!(StepOutcome::StepLimit(7), interpreter.run(None));
+        assert_eq!(15, interpreter.steps());
+
+        // the 2020 loop detection stops it right away, even in the extended dialect
+        let mut interpreter = Interpreter::with_observers(
+            program,
+            FaultPolicy::Trap,
+            vec![Box::new(LoopDetector::default())],
+        );
+        assert_eq!(StepOutcome::InfiniteLoop(1), interpreter.run(None));
+    }
+
+    #[test]
+    fn test_custom_observer_and_tracer() {
+        let program = extended("add r1 +2\nout r1\nadd r1 -3\nout r1\nout acc");
+        let trace = SharedOutput::default();
+        let mut interpreter = Interpreter::new(program);
+        interpreter.add_observer(Box::new(NoNegativeOutput));
+        interpreter.add_observer(Box::new(Tracer::new(trace.clone())));
+        assert_eq!(StepOutcome::Terminate(0), interpreter.run(None));
+        assert_eq!(vec![2], interpreter.output);
+        assert_eq!(3, interpreter.instruction_pointer);
+        assert_eq!(
+            String::from_utf8(trace.0.borrow().clone()).unwrap(),
+            "\
+step\tindex\tinstruction\taccumulator_before\taccumulator_after
+0\t0\tadd r1 +2\t0\t0
+1\t1\tout r1\t0\t0
+2\t2\tadd r1 -3\t0\t0
+"
+        );
+    }
+
+    #[test]
+    fn test_step_back_undoes_observers() {
+        let program = extended("add r1 +1\njz r1 +2\nadd r1 -1\njmp -2");
+        let mut interpreter = Interpreter::with_observers(
+            program,
+            FaultPolicy::Trap,
+            vec![
+                Box::new(LoopDetector::default()),
+                Box::new(StateLoopDetector::default()),
+            ],
+        );
+        interpreter.keep_history();
+        assert_eq!(StepOutcome::InfiniteLoop(0), interpreter.run(None));
+        assert_eq!(4, interpreter.trace().len());
+        assert!(interpreter.rewind(1));
+        assert_eq!(StepOutcome::InfiniteLoop(0), interpreter.run(None));
+        assert_eq!(4, interpreter.trace().len());
+    }
+}
@@ -0,0 +1,405 @@
+use crate::{Instruction, Interpreter, Program, StepOutcome};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct OptimizeStats {
+    pub instructions_before: usize,
+    pub instructions_after: usize,
+    pub jumps_threaded: usize,
+    pub accs_merged: usize, // ACC instructions folded into the one before them
+    pub unreachable_removed: usize,
+}
+
+impl fmt::Display for OptimizeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "instructions before: {}", self.instructions_before)?;
+        writeln!(f, "jumps threaded: {}", self.jumps_threaded)?;
+        writeln!(
+            f,
+            "unreachable instructions removed: {}",
+            self.unreachable_removed
+        )?;
+        writeln!(f, "acc instructions merged: {}", self.accs_merged)?;
+        write!(f, "instructions after: {}", self.instructions_after)
+    }
+}
+
+// the absolute target of a jump (None for other instructions), which may lie outside the program
+fn jump_target(instruction: Instruction, index: usize) -> Option<i64> {
+    match instruction {
+        Instruction::JMP(argument)
+        | Instruction::JZ(_, argument)
+        | Instruction::JNZ(_, argument) => Some((index as i64).saturating_add(argument)),
+        _ => None,
+    }
+}
+
+fn in_program(target: i64, len: usize) -> Option<usize> {
+    if target >= 0 && target <= len as i64 {
+        Some(target as usize)
+    } else {
+        None
+    }
+}
+
+// for every index (plus one past the end), where execution really continues when landing there:
+// the first instruction that is not an unconditional jump within the program.
+// Jumps don't touch the registers, so skipping them changes neither the outcome nor the accumulator;
+// a cycle of jumps resolves to the index where it was entered.
+fn resolve_jumps(instructions: &[Instruction]) -> Vec<usize> {
+    let len = instructions.len();
+    let next = |index: usize| match instructions.get(index) {
+        Some(&Instruction::JMP(argument)) => {
+            in_program((index as i64).saturating_add(argument), len)
+        }
+        _ => None,
+    };
+    let mut resolved: Vec<Option<usize>> = vec![None; len + 1];
+    let mut on_path = vec![false; len + 1];
+    for start in 0..=len {
+        let mut path = Vec::new();
+        let mut index = start;
+        let end = loop {
+            if let Some(end) = resolved[index] {
+                break end;
+            }
+            if on_path[index] {
+                break index;
+            }
+            match next(index) {
+                Some(target) => {
+                    on_path[index] = true;
+                    path.push(index);
+                    index = target;
+                }
+                None => break index,
+            }
+        };
+        resolved[index].get_or_insert(end);
+        for index in path {
+            resolved[index] = Some(end);
+            on_path[index] = false;
+        }
+    }
+    resolved
+        .into_iter()
+        .map(|end| end.expect("unresolved index"))
+        .collect()
+}
+
+fn reachable(instructions: &[Instruction]) -> Vec<bool> {
+    let len = instructions.len();
+    let mut reachable = vec![false; len];
+    let mut queue = VecDeque::new();
+    if len > 0 {
+        reachable[0] = true;
+        queue.push_back(0);
+    }
+    while let Some(index) = queue.pop_front() {
+        let instruction = instructions[index];
+        let falls_through = match instruction {
+            Instruction::JMP(_) | Instruction::HLT => None,
+            _ => Some(index + 1),
+        };
+        let jumps_to = jump_target(instruction, index).and_then(|target| in_program(target, len));
+        for successor in falls_through.into_iter().chain(jumps_to) {
+            if successor < len && !reachable[successor] {
+                reachable[successor] = true;
+                queue.push_back(successor);
+            }
+        }
+    }
+    reachable
+}
+
+fn with_argument(instruction: Instruction, argument: i64) -> Instruction {
+    match instruction {
+        Instruction::JMP(_) => Instruction::JMP(argument),
+        Instruction::JZ(register, _) => Instruction::JZ(register, argument),
+        Instruction::JNZ(register, _) => Instruction::JNZ(register, argument),
+        _ => instruction,
+    }
+}
+
+/// Thread jumps that land on other jumps, remove unreachable instructions
+/// and merge runs of ACC instructions, rewriting the relative jumps to match.
+/// The result runs to the same outcome with the same accumulator and output
+/// (with the default FaultPolicy::Trap), but NOP arguments are kept as they are,
+/// so it is not meant to be repaired. Linear in the length of the program, apart from hashing.
+pub fn optimize(program: &Program) -> (Program, OptimizeStats) {
+    let len = program.instructions.len();
+    let mut stats = OptimizeStats {
+        instructions_before: len,
+        ..OptimizeStats::default()
+    };
+
+    let resolved = resolve_jumps(&program.instructions);
+    let mut instructions = program.instructions.clone();
+    for (index, instruction) in instructions.iter_mut().enumerate() {
+        if let Some(target) = jump_target(*instruction, index).and_then(|t| in_program(t, len)) {
+            if resolved[target] != target {
+                *instruction = with_argument(*instruction, resolved[target] as i64 - index as i64);
+                stats.jumps_threaded += 1;
+            }
+        }
+    }
+
+    let reachable = reachable(&instructions);
+    let targets: HashSet<usize> = (0..len)
+        .filter(|&index| reachable[index])
+        .filter_map(|index| jump_target(instructions[index], index))
+        .filter_map(|target| in_program(target, len))
+        .collect();
+    let mut kept = vec![false; len];
+    // the ACC that the current run is merged into; runs only have one sign,
+    // so the accumulator passes through the same values and overflows exactly when the original does
+    let mut run: Option<usize> = None;
+    for index in 0..len {
+        if !reachable[index] {
+            stats.unreachable_removed += 1;
+            run = None;
+            continue;
+        }
+        if let (Some(head), Instruction::ACC(argument)) = (run, instructions[index]) {
+            if let Instruction::ACC(total) = instructions[head] {
+                let same_sign = (total >= 0 && argument >= 0) || (total <= 0 && argument <= 0);
+                match total.checked_add(argument) {
+                    Some(sum) if same_sign && !targets.contains(&index) => {
+                        instructions[head] = Instruction::ACC(sum);
+                        stats.accs_merged += 1;
+                        continue;
+                    }
+                    _ => (),
+                }
+            }
+        }
+        kept[index] = true;
+        run = match instructions[index] {
+            Instruction::ACC(_) => Some(index),
+            _ => None,
+        };
+    }
+
+    // new_index[index] is the number of instructions kept before index,
+    // which for every jump target (and the end) is where it ends up
+    let mut new_index = Vec::with_capacity(len + 1);
+    let mut count = 0;
+    for &kept in &kept {
+        new_index.push(count);
+        count += kept as usize;
+    }
+    new_index.push(count);
+    let new_len = count;
+
+    let mut optimized = Vec::with_capacity(new_len);
+    for index in (0..len).filter(|&index| kept[index]) {
+        let instruction = instructions[index];
+        let from = new_index[index] as i64;
+        optimized.push(match jump_target(instruction, index) {
+            Some(target) if target < 0 => with_argument(instruction, target - from),
+            Some(target) if target > len as i64 => {
+                let past_end = (new_len as i64).saturating_add(target - len as i64);
+                with_argument(instruction, past_end - from)
+            }
+            Some(target) => with_argument(instruction, new_index[target as usize] as i64 - from),
+            None => instruction,
+        });
+    }
+    stats.instructions_after = optimized.len();
+    (
+        // keeping the dialect keeps how loops are detected, even without extended instructions
+        Program {
+            instructions: optimized,
+            dialect: program.dialect,
+        },
+        stats,
+    )
+}
+
+/// Steps that equivalent gives each program by default: extended programs only loop
+/// once their whole state repeats, which a counting loop takes about 2^63 steps to do.
+pub const EQUIVALENCE_BUDGET: usize = 1_000_000;
+
+// what running a program amounts to: its outcome, final accumulator and output,
+// or None if it doesn't stop within the budget
+fn behaviour(program: &Program, budget: usize) -> Option<(StepOutcome, i64, Vec<i64>)> {
+    let mut interpreter = Interpreter::new(program.clone());
+    match interpreter.run(Some(budget)) {
+        StepOutcome::Continue => None,
+        outcome => Some((outcome, interpreter.accumulator, interpreter.output)),
+    }
+}
+
+/// Whether both programs stop with the same outcome, accumulator and output,
+/// or None (undecided) if either of them doesn't stop within budget steps.
+pub fn equivalent(program: &Program, other: &Program, budget: usize) -> Option<bool> {
+    Some(behaviour(program, budget)? == behaviour(other, budget)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::{self, GeneratorOptions};
+    use crate::repair;
+    use crate::Dialect;
+    use pretty_assertions::assert_eq;
+
+    fn optimized(source: &str, dialect: Dialect) -> (String, OptimizeStats) {
+        let program = Program::parse_dialect(source, dialect).unwrap();
+        let (optimized, stats) = optimize(&program);
+        assert_eq!(
+            Some(true),
+            equivalent(&program, &optimized, EQUIVALENCE_BUDGET)
+        );
+        (optimized.to_string(), stats)
+    }
+
+    #[test]
+    fn test_optimize() {
+        assert_eq!(
+            optimized(
+                "
+                acc +1
+                acc +2
+                jmp a
+                acc +99
+                a: jmp b
+                b: acc +3
+                loop: acc -1
+                acc -2
+                jmp loop
+                ",
+                Dialect::Aoc2020
+            ),
+            (
+                "acc +3\njmp +1\nacc +3\nacc -3\njmp -1\n".to_owned(),
+                OptimizeStats {
+                    instructions_before: 9,
+                    instructions_after: 5,
+                    jumps_threaded: 1,
+                    accs_merged: 2,
+                    unreachable_removed: 2,
+                }
+            )
+        );
+        // no merging across signs, jump targets or into overflow
+        let source = "
+            acc +1
+            acc -1
+            t: acc +1
+            acc +9223372036854775807
+            jmp t
+        ";
+        assert_eq!(
+            optimized(source, Dialect::Aoc2020).0,
+            "acc +1\nacc -1\nacc +1\nacc +9223372036854775807\njmp -2\n"
+        );
+    }
+
+    #[test]
+    fn test_optimize_jumps_out_of_program() {
+        assert_eq!(
+            optimized("jmp a\nacc +5\nacc +5\na: jmp -7", Dialect::Aoc2020).0,
+            "jmp +1\njmp -5\n"
+        );
+        assert_eq!(
+            optimized("jmp a\nacc +5\na: jmp +5\nacc +1", Dialect::Aoc2020).0,
+            "jmp +1\njmp +4\n"
+        );
+        assert_eq!(
+            optimized("jmp a\nb: jmp a\na: jmp b", Dialect::Aoc2020).0,
+            "jmp +1\njmp +0\n"
+        );
+    }
+
+    #[test]
+    fn test_optimize_extended() {
+        let (source, stats) = optimized(
+            "
+            add r1 +3
+            loop: acc +1
+            acc +1
+            add r1 -1
+            jnz r1 skip
+            jmp end
+            skip: jmp loop
+            end:
+            ",
+            Dialect::Extended,
+        );
+        assert_eq!(
+            source,
+            ".dialect extended\nadd r1 +3\nacc +2\nadd r1 -1\njnz r1 -2\njmp +1\n"
+        );
+        assert_eq!(1, stats.jumps_threaded);
+        // the loop is still detected by state, not by instruction, as the dialect is kept
+        let (source, _stats) = optimized(
+            "
+            loop: acc +1
+            acc -1
+            jmp loop
+            out r1
+            ",
+            Dialect::Extended,
+        );
+        assert_eq!(source, ".dialect extended\nacc +1\nacc -1\njmp -2\n");
+    }
+
+    #[test]
+    fn test_equivalence_budget() {
+        // counts up until the accumulator overflows, which takes far too long to wait for
+        let program = Program::parse_dialect("loop: acc +1\njmp loop", Dialect::Extended).unwrap();
+        let (optimized, _stats) = optimize(&program);
+        assert_eq!(None, equivalent(&program, &optimized, 1000));
+        let program = Program::parse_dialect("acc +1\nacc +1\nout acc", Dialect::Extended).unwrap();
+        let (optimized, _stats) = optimize(&program);
+        // stopping at the end takes one more step than there are instructions
+        assert_eq!(Some(true), equivalent(&program, &optimized, 4));
+        assert_eq!(None, equivalent(&program, &optimized, 3));
+        let other = Program::parse_dialect("acc +2\nout r1", Dialect::Extended).unwrap();
+        assert_eq!(Some(false), equivalent(&program, &other, 4));
+    }
+
+    #[test]
+    fn test_optimize_is_equivalent() {
+        let operations = [Instruction::ACC, Instruction::JMP, Instruction::NOP];
+        let instructions: Vec<Instruction> = operations
+            .iter()
+            .flat_map(|&operation| (-4..=4).map(operation))
+            .collect();
+        for &a in &instructions {
+            for &b in &instructions {
+                for &c in &instructions {
+                    let program = Program::new(vec![a, b, c]);
+                    assert_eq!(
+                        Some(true),
+                        equivalent(&program, &optimize(&program).0, EQUIVALENCE_BUDGET),
+                        "{}",
+                        program
+                    );
+                }
+            }
+        }
+        let options = GeneratorOptions {
+            len: 2000,
+            ..GeneratorOptions::default()
+        };
+        for seed in 0..5 {
+            let program = generator::generate(seed, &options).unwrap().program;
+            let (optimized, stats) = optimize(&program);
+            assert!(stats.instructions_after < stats.instructions_before);
+            assert_eq!(
+                Some(true),
+                equivalent(&program, &optimized, EQUIVALENCE_BUDGET)
+            );
+            let mut repaired = program.clone();
+            let index = repair::repair(&program).unwrap().index;
+            repaired.instructions[index] = repaired.instructions[index].toggle().unwrap();
+            assert_eq!(
+                Some(true),
+                equivalent(&repaired, &optimize(&repaired).0, EQUIVALENCE_BUDGET)
+            );
+        }
+    }
+}
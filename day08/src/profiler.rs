@@ -0,0 +1,176 @@
+use crate::{Instruction, Interpreter, Program, StepOutcome};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Profile {
+    pub hits: Vec<u64>,                       // by instruction index
+    pub jumps: BTreeMap<(usize, usize), u64>, // taken jumps by (source, target)
+}
+
+impl Profile {
+    /// Run the interpreter until it stops stepping, counting executed instructions and taken jumps.
+    pub fn run(interpreter: &mut Interpreter) -> (StepOutcome, Profile) {
+        let mut profile = Profile {
+            hits: vec![0; interpreter.program.instructions.len()],
+            jumps: BTreeMap::new(),
+        };
+        loop {
+            let source = interpreter.instruction_pointer;
+            match interpreter.step() {
+                StepOutcome::Continue => (),
+                outcome => return (outcome, profile),
+            }
+            profile.hits[source] += 1;
+            // jumps don't change registers, so the condition can still be checked afterwards
+            let taken = match interpreter.program.instructions[source] {
+                Instruction::JMP(_argument) => true,
+                Instruction::JZ(register, _argument) => interpreter.register(register) == 0,
+                Instruction::JNZ(register, _argument) => interpreter.register(register) != 0,
+                _ => false,
+            };
+            if taken {
+                *profile
+                    .jumps
+                    .entry((source, interpreter.instruction_pointer))
+                    .or_default() += 1;
+            }
+        }
+    }
+
+    pub fn unexecuted(&self) -> Vec<usize> {
+        (0..self.hits.len())
+            .filter(|&index| self.hits[index] == 0)
+            .collect()
+    }
+
+    fn jumps_from(&self, source: usize) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.jumps
+            .range((source, 0)..=(source, usize::MAX))
+            .map(|(&(_source, target), &count)| (target, count))
+    }
+
+    /// The program with hit counts next to each instruction (##### if it never ran),
+    /// taken jumps after the instruction, and a coverage summary at the end.
+    pub fn listing(&self, program: &Program) -> String {
+        let mut listing = String::new();
+        for (index, instruction) in program.instructions.iter().enumerate() {
+            let hits = match self.hits[index] {
+                0 => "#####".to_owned(),
+                hits => hits.to_string(),
+            };
+            write!(listing, "{:>9}: {:>5}: {}", hits, index, instruction).unwrap();
+            for (target, count) in self.jumps_from(index) {
+                write!(listing, " # {}x to {}", count, target).unwrap();
+            }
+            listing.push('\n');
+        }
+        let executed = self.hits.len() - self.unexecuted().len();
+        writeln!(
+            listing,
+            "coverage: {}/{} instructions",
+            executed,
+            self.hits.len()
+        )
+        .unwrap();
+        listing
+    }
+
+    pub fn csv(&self, program: &Program) -> String {
+        let mut csv = String::from("index,instruction,hits,jump_target,jumps_taken\n");
+        for (index, instruction) in program.instructions.iter().enumerate() {
+            let mut jumps = self.jumps_from(index).peekable();
+            if jumps.peek().is_none() {
+                writeln!(csv, "{},{},{},,0", index, instruction, self.hits[index]).unwrap();
+            }
+            for (target, count) in jumps {
+                writeln!(
+                    csv,
+                    "{},{},{},{},{}",
+                    index, instruction, self.hits[index], target, count
+                )
+                .unwrap();
+            }
+        }
+        csv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Dialect;
+    use pretty_assertions::assert_eq;
+
+    fn sample_program() -> Program {
+        Program::new(vec![
+            Instruction::NOP(0),
+            Instruction::ACC(1),
+            Instruction::JMP(4),
+            Instruction::ACC(3),
+            Instruction::JMP(-3),
+            Instruction::ACC(-99),
+            Instruction::ACC(1),
+            Instruction::JMP(-4),
+            Instruction::ACC(6),
+        ])
+    }
+
+    #[test]
+    fn test_profile() {
+        let (outcome, profile) = Profile::run(&mut Interpreter::new(sample_program()));
+        assert_eq!(StepOutcome::InfiniteLoop(5), outcome);
+        assert_eq!(vec![1, 1, 1, 1, 1, 0, 1, 1, 0], profile.hits);
+        assert_eq!(vec![5, 8], profile.unexecuted());
+        assert_eq!(
+            profile.listing(&sample_program()),
+            "        \
+        1:     0: nop +0
+        1:     1: acc +1
+        1:     2: jmp +4 # 1x to 6
+        1:     3: acc +3
+        1:     4: jmp -3 # 1x to 1
+    #####:     5: acc -99
+        1:     6: acc +1
+        1:     7: jmp -4 # 1x to 3
+    #####:     8: acc +6
+coverage: 7/9 instructions
+"
+        );
+        assert_eq!(
+            profile.csv(&sample_program()),
+            "\
+index,instruction,hits,jump_target,jumps_taken
+0,nop +0,1,,0
+1,acc +1,1,,0
+2,jmp +4,1,6,1
+3,acc +3,1,,0
+4,jmp -3,1,1,1
+5,acc -99,0,,0
+6,acc +1,1,,0
+7,jmp -4,1,3,1
+8,acc +6,0,,0
+"
+        );
+    }
+
+    #[test]
+    fn test_profile_conditional_jumps() {
+        let program = Program::parse_dialect(
+            "
+            add r1 +3
+            loop: add r1 -1
+            jnz r1 loop
+            ",
+            Dialect::Extended,
+        )
+        .unwrap();
+        let (outcome, profile) = Profile::run(&mut Interpreter::new(program));
+        assert_eq!(StepOutcome::Terminate(0), outcome);
+        assert_eq!(vec![1, 3, 3], profile.hits);
+        assert_eq!(
+            vec![((2, 1), 2)],
+            profile.jumps.into_iter().collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,714 @@
+use crate::{Dialect, Instruction, Program};
+use std::collections::{HashMap, VecDeque};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Repair {
+    pub index: usize,
+    pub accumulator: i64,
+}
+
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Edit {
+    pub index: usize,
+    pub instruction: Instruction, // the replacement instruction
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MultiRepair {
+    pub edits: Vec<Edit>, // sorted by index
+    pub accumulator: i64,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RepairOptions {
+    pub max_edits: usize,
+    // besides toggling JMP/NOP, also swap ACC for JMP/NOP and vice versa, keeping the argument
+    pub swap_acc: bool,
+    // also change the argument of (at most) one ACC per repair, to the one closest to the original
+    // that keeps the accumulator from overflowing on the way to termination
+    pub edit_acc: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MinimalRepairs {
+    pub repairs: Vec<MultiRepair>,
+    pub unique: bool,
+}
+
+// the index of the instruction executed after the one at index,
+// where program.instructions.len() stands for termination
+// and None for a jump that faults (see FaultPolicy::Trap);
+// only the 2020 dialect is supported, the control flow of later ones depends on registers
+fn successor(instruction: Instruction, index: usize, len: usize) -> Option<usize> {
+    let target = match instruction {
+        Instruction::ACC(_argument) | Instruction::NOP(_argument) => index as i64 + 1,
+        Instruction::JMP(argument) => (index as i64).saturating_add(argument),
+        _ => unreachable!("not in the 2020 dialect: {}", instruction),
+    };
+    if target < 0 || target > len as i64 {
+        None
+    } else {
+        Some(target as usize)
+    }
+}
+
+fn accumulated(instruction: Instruction) -> i64 {
+    match instruction {
+        Instruction::ACC(argument) => argument,
+        _ => 0,
+    }
+}
+
+/// How running from an instruction to termination changes the accumulator:
+/// by gain in the end, and by at least low and at most high on the way
+/// (wide enough to never overflow themselves).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Ending {
+    gain: i128,
+    low: i128,
+    high: i128,
+}
+
+impl Ending {
+    const TERMINATION: Ending = Ending {
+        gain: 0,
+        low: 0,
+        high: 0,
+    };
+
+    // the same ending, preceded by adding argument to the accumulator
+    fn after(self, argument: i64) -> Ending {
+        let argument = i128::from(argument);
+        Ending {
+            gain: argument + self.gain,
+            low: (argument + self.low).min(0),
+            high: (argument + self.high).max(0),
+        }
+    }
+
+    // the final accumulator when starting with this one,
+    // or None if it overflows on the way (which faults instead of terminating)
+    fn from(self, accumulator: i64) -> Option<i64> {
+        let accumulator = i128::from(accumulator);
+        if accumulator + self.low < i128::from(i64::MIN)
+            || accumulator + self.high > i128::from(i64::MAX)
+        {
+            return None;
+        }
+        Some((accumulator + self.gain) as i64)
+    }
+
+    // the ACC argument closest to the given one that, added to the accumulator before this ending,
+    // doesn't overflow on the way, or None if there is no such argument
+    fn argument_from(self, accumulator: i64, argument: i64) -> Option<i64> {
+        let accumulator = i128::from(accumulator);
+        let low = (i128::from(i64::MIN) - self.low - accumulator).max(i128::from(i64::MIN));
+        let high = (i128::from(i64::MAX) - self.high - accumulator).min(i128::from(i64::MAX));
+        if low > high {
+            return None;
+        }
+        Some(i128::from(argument).max(low).min(high) as i64)
+    }
+}
+
+/// For every instruction index (plus one past the end),
+/// how the accumulator changes by running from there to termination,
+/// or None if the instruction does not lead to termination.
+pub fn accumulator_to_end(program: &Program) -> Vec<Option<Ending>> {
+    let len = program.instructions.len();
+    let mut predecessors = vec![Vec::new(); len + 1];
+    for (index, &instruction) in program.instructions.iter().enumerate() {
+        if let Some(successor) = successor(instruction, index, len) {
+            predecessors[successor].push(index);
+        }
+    }
+
+    let mut accumulator_to_end = vec![None; len + 1];
+    accumulator_to_end[len] = Some(Ending::TERMINATION);
+    let mut queue = VecDeque::new();
+    queue.push_back(len);
+    while let Some(index) = queue.pop_front() {
+        let ending = accumulator_to_end[index].expect("queued index without ending");
+        for &predecessor in &predecessors[index] {
+            accumulator_to_end[predecessor] =
+                Some(ending.after(accumulated(program.instructions[predecessor])));
+            queue.push_back(predecessor);
+        }
+    }
+    accumulator_to_end
+}
+
+/// Find the single JMP/NOP toggle that makes the program terminate,
+/// using the control-flow graph instead of re-running the program for every candidate.
+/// Returns None if the program already terminates or no single toggle repairs it,
+/// or if the program has instructions beyond the 2020 dialect.
+/// A toggle after which the accumulator overflows doesn't repair the program, as that faults.
+pub fn repair(program: &Program) -> Option<Repair> {
+    if program.oldest_dialect() != Dialect::Aoc2020 {
+        return None;
+    }
+    let len = program.instructions.len();
+    let accumulator_to_end = accumulator_to_end(program);
+    if accumulator_to_end[0]
+        .and_then(|ending| ending.from(0))
+        .is_some()
+    {
+        return None;
+    }
+
+    let mut best: Option<Repair> = None;
+    let mut seen = vec![false; len];
+    let mut index = 0;
+    let mut accumulator = 0;
+    while index < len && !seen[index] {
+        seen[index] = true;
+        let instruction = program.instructions[index];
+        if let Some(toggled) = instruction.toggle() {
+            if let Some(accumulator) = successor(toggled, index, len)
+                .and_then(|successor| accumulator_to_end[successor])
+                .and_then(|ending| ending.from(accumulator))
+            {
+                if best.is_none_or(|best| index < best.index) {
+                    best = Some(Repair { index, accumulator });
+                }
+            }
+        }
+        // every later toggle would run into the same overflow
+        accumulator = match accumulator.checked_add(accumulated(instruction)) {
+            Some(accumulator) => accumulator,
+            None => break,
+        };
+        index = match successor(instruction, index, len) {
+            Some(successor) => successor,
+            None => break,
+        };
+    }
+    best
+}
+
+// the instructions an edit can replace instruction with
+fn alternatives(instruction: Instruction, swap_acc: bool) -> Vec<Instruction> {
+    match instruction {
+        Instruction::ACC(argument) if swap_acc => {
+            vec![Instruction::JMP(argument), Instruction::NOP(argument)]
+        }
+        Instruction::ACC(_argument) => vec![],
+        Instruction::JMP(argument) | Instruction::NOP(argument) => {
+            let mut alternatives = vec![instruction.toggle().expect("JMP/NOP can be toggled")];
+            if swap_acc {
+                alternatives.push(Instruction::ACC(argument));
+            }
+            alternatives
+        }
+        _ => vec![],
+    }
+}
+
+// the instructions executed by the program (with the accumulator before each one),
+// and the final accumulator if the program terminates afterwards (rather than looping or faulting);
+// the control flow doesn't depend on the accumulator, so the path goes on after an overflow,
+// with None as the accumulator from there on
+fn execution_path(program: &Program) -> (Vec<(usize, Option<i64>)>, Option<i64>) {
+    let len = program.instructions.len();
+    let mut path = Vec::new();
+    let mut seen = vec![false; len];
+    let mut index = 0;
+    let mut accumulator: Option<i64> = Some(0);
+    while index < len && !seen[index] {
+        seen[index] = true;
+        path.push((index, accumulator));
+        let instruction = program.instructions[index];
+        accumulator =
+            accumulator.and_then(|accumulator| accumulator.checked_add(accumulated(instruction)));
+        index = match successor(instruction, index, len) {
+            Some(successor) => successor,
+            None => return (path, None),
+        };
+    }
+    if index == len {
+        (path, accumulator)
+    } else {
+        (path, None)
+    }
+}
+
+fn insert_repair(
+    found: &mut HashMap<Vec<Edit>, i64>,
+    edits: &[Edit],
+    edit: Edit,
+    accumulator: i64,
+) {
+    let mut repair = edits.to_vec();
+    repair.push(edit);
+    repair.sort_by_key(|edit| edit.index);
+    found.insert(repair, accumulator);
+}
+
+// Collect all repairs that consist of the given edits (already applied to the program)
+// plus exactly `remaining` more edits.
+// Every repair has to change at least one instruction on the current execution path,
+// so only those are tried, and the last edit is found with the control-flow graph
+// instead of trying and running every candidate.
+// An edit after the accumulator overflowed can't prevent that overflow,
+// so those are only tried if an ACC argument can still be edited before it (always as the last edit).
+fn search(
+    program: &mut Program,
+    edits: &mut Vec<Edit>,
+    remaining: usize,
+    options: RepairOptions,
+    found: &mut HashMap<Vec<Edit>, i64>,
+) {
+    let len = program.instructions.len();
+    let (path, accumulator) = execution_path(program);
+    if accumulator.is_some() {
+        // a subset of the edits is already a repair
+        return;
+    }
+    let accumulator_to_end = if remaining == 1 {
+        accumulator_to_end(program)
+    } else {
+        vec![]
+    };
+    for (index, accumulator) in path {
+        if edits.iter().any(|edit| edit.index == index) {
+            continue;
+        }
+        let original = program.instructions[index];
+        if remaining == 1 {
+            let accumulator = match accumulator {
+                Some(accumulator) => accumulator,
+                None => break,
+            };
+            for alternative in alternatives(original, options.swap_acc) {
+                if let Some(accumulator) = successor(alternative, index, len)
+                    .and_then(|successor| accumulator_to_end[successor])
+                    .and_then(|ending| ending.after(accumulated(alternative)).from(accumulator))
+                {
+                    let edit = Edit {
+                        index,
+                        instruction: alternative,
+                    };
+                    insert_repair(found, edits, edit, accumulator);
+                }
+            }
+            if let (Instruction::ACC(argument), true) = (original, options.edit_acc) {
+                let ending = accumulator_to_end[index + 1];
+                if let Some(edited) = ending
+                    .and_then(|ending| ending.argument_from(accumulator, argument))
+                    .filter(|&edited| edited != argument)
+                {
+                    let accumulator = ending
+                        .and_then(|ending| ending.after(edited).from(accumulator))
+                        .expect("edited ACC argument overflows");
+                    let edit = Edit {
+                        index,
+                        instruction: Instruction::ACC(edited),
+                    };
+                    insert_repair(found, edits, edit, accumulator);
+                }
+            }
+        } else {
+            if accumulator.is_none() && !options.edit_acc {
+                break;
+            }
+            for alternative in alternatives(original, options.swap_acc) {
+                program.instructions[index] = alternative;
+                edits.push(Edit {
+                    index,
+                    instruction: alternative,
+                });
+                search(program, edits, remaining - 1, options, found);
+                edits.pop();
+            }
+            program.instructions[index] = original;
+        }
+    }
+}
+
+/// Find all smallest sets of at most options.max_edits edits that make the program terminate.
+/// If the program already terminates, the only minimal repair is the empty one.
+/// Programs with instructions beyond the 2020 dialect cannot be repaired,
+/// and edits after which the accumulator overflows are no repairs, as that faults.
+pub fn repair_minimal(program: &Program, options: RepairOptions) -> MinimalRepairs {
+    if program.oldest_dialect() != Dialect::Aoc2020 {
+        return MinimalRepairs {
+            repairs: vec![],
+            unique: false,
+        };
+    }
+    if let (_path, Some(accumulator)) = execution_path(program) {
+        return MinimalRepairs {
+            repairs: vec![MultiRepair {
+                edits: vec![],
+                accumulator,
+            }],
+            unique: true,
+        };
+    }
+
+    let mut program = program.clone();
+    for edits in 1..=options.max_edits {
+        let mut found = HashMap::new();
+        search(&mut program, &mut vec![], edits, options, &mut found);
+        if !found.is_empty() {
+            let mut repairs: Vec<MultiRepair> = found
+                .into_iter()
+                .map(|(edits, accumulator)| MultiRepair { edits, accumulator })
+                .collect();
+            repairs.sort_by_key(|repair| {
+                repair
+                    .edits
+                    .iter()
+                    .map(|edit| (edit.index, edit.instruction.to_string()))
+                    .collect::<Vec<_>>()
+            });
+            return MinimalRepairs {
+                unique: repairs.len() == 1,
+                repairs,
+            };
+        }
+    }
+    MinimalRepairs {
+        repairs: vec![],
+        unique: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_repair() {
+        let program = Program::new(vec![
+            Instruction::NOP(0),
+            Instruction::ACC(1),
+            Instruction::JMP(4),
+            Instruction::ACC(3),
+            Instruction::JMP(-3),
+            Instruction::ACC(-99),
+            Instruction::ACC(1),
+            Instruction::JMP(-4),
+            Instruction::ACC(6),
+        ]);
+
+        assert_eq!(
+            Some(Repair {
+                index: 7,
+                accumulator: 8
+            }),
+            repair(&program)
+        );
+    }
+
+    #[test]
+    fn test_repair_terminating_program() {
+        let program = Program::new(vec![Instruction::NOP(0), Instruction::ACC(1)]);
+
+        assert_eq!(None, repair(&program));
+    }
+
+    #[test]
+    fn test_repair_matches_brute_force() {
+        let program = Program::new(vec![
+            Instruction::ACC(2),
+            Instruction::JMP(2),
+            Instruction::ACC(100),
+            Instruction::NOP(3),
+            Instruction::ACC(10),
+            Instruction::JMP(-3),
+            Instruction::ACC(7),
+        ]);
+
+        // toggling 3 or 5 both terminate, but the brute force finds 3 first
+        assert_eq!(9, crate::part2_brute_force(program.clone()));
+        assert_eq!(
+            Some(Repair {
+                index: 3,
+                accumulator: 9
+            }),
+            repair(&program)
+        );
+    }
+
+    #[test]
+    fn test_repair_overflow() {
+        // toggling 1 would terminate, but only after the accumulator overflows
+        let program: Program = "
+            acc +9223372036854775807
+            nop +3
+            acc -1
+            jmp +0
+            acc +1
+        "
+        .parse()
+        .unwrap();
+        assert_eq!(
+            Some(Repair {
+                index: 3,
+                accumulator: i64::MAX
+            }),
+            repair(&program)
+        );
+        let options = RepairOptions {
+            max_edits: 1,
+            swap_acc: false,
+            edit_acc: false,
+        };
+        assert_eq!(
+            vec![MultiRepair {
+                edits: vec![Edit {
+                    index: 3,
+                    instruction: Instruction::NOP(0)
+                }],
+                accumulator: i64::MAX
+            }],
+            repair_minimal(&program, options).repairs
+        );
+
+        // the overflow happens on the way, not in the final accumulator
+        let program: Program = "
+            acc +9223372036854775807
+            nop +2
+            jmp +0
+            acc +1
+            acc -2
+        "
+        .parse()
+        .unwrap();
+        assert_eq!(None, repair(&program));
+
+        // the program faults before it loops
+        let program: Program = "
+            acc +9223372036854775807
+            nop +0
+            acc +1
+            jmp -2
+        "
+        .parse()
+        .unwrap();
+        assert_eq!(None, repair(&program));
+        assert_eq!(0, repair_minimal(&program, options).repairs.len());
+    }
+
+    #[test]
+    fn test_repair_minimal_single() {
+        let program = Program::new(vec![
+            Instruction::NOP(0),
+            Instruction::ACC(1),
+            Instruction::JMP(4),
+            Instruction::ACC(3),
+            Instruction::JMP(-3),
+            Instruction::ACC(-99),
+            Instruction::ACC(1),
+            Instruction::JMP(-4),
+            Instruction::ACC(6),
+        ]);
+
+        assert_eq!(
+            MinimalRepairs {
+                repairs: vec![MultiRepair {
+                    edits: vec![Edit {
+                        index: 7,
+                        instruction: Instruction::NOP(-4)
+                    }],
+                    accumulator: 8
+                }],
+                unique: true
+            },
+            repair_minimal(
+                &program,
+                RepairOptions {
+                    max_edits: 3,
+                    swap_acc: false,
+                    edit_acc: false,
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn test_repair_minimal_two_edits() {
+        let program: Program = "
+            acc +1
+            jmp +0 # loop here
+            acc +2
+            nop +3
+            jmp -1 # and here
+            acc +4
+        "
+        .parse()
+        .unwrap();
+        let options = RepairOptions {
+            max_edits: 1,
+            swap_acc: false,
+            edit_acc: false,
+        };
+
+        assert_eq!(
+            MinimalRepairs {
+                repairs: vec![],
+                unique: false
+            },
+            repair_minimal(&program, options)
+        );
+        assert_eq!(
+            MinimalRepairs {
+                repairs: vec![
+                    MultiRepair {
+                        edits: vec![
+                            Edit {
+                                index: 1,
+                                instruction: Instruction::NOP(0)
+                            },
+                            Edit {
+                                index: 3,
+                                instruction: Instruction::JMP(3)
+                            }
+                        ],
+                        accumulator: 3
+                    },
+                    MultiRepair {
+                        edits: vec![
+                            Edit {
+                                index: 1,
+                                instruction: Instruction::NOP(0)
+                            },
+                            Edit {
+                                index: 4,
+                                instruction: Instruction::NOP(-1)
+                            }
+                        ],
+                        accumulator: 7
+                    },
+                ],
+                unique: false
+            },
+            repair_minimal(
+                &program,
+                RepairOptions {
+                    max_edits: 2,
+                    ..options
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn test_repair_minimal_swap_acc() {
+        let program: Program = "
+            nop +0
+            acc +2
+            jmp -1
+            acc +4
+        "
+        .parse()
+        .unwrap();
+
+        let repairs = repair_minimal(
+            &program,
+            RepairOptions {
+                max_edits: 2,
+                swap_acc: true,
+                edit_acc: false,
+            },
+        );
+        assert_eq!(
+            vec![
+                MultiRepair {
+                    edits: vec![Edit {
+                        index: 1,
+                        instruction: Instruction::JMP(2)
+                    }],
+                    accumulator: 4,
+                },
+                MultiRepair {
+                    edits: vec![Edit {
+                        index: 2,
+                        instruction: Instruction::ACC(-1)
+                    }],
+                    accumulator: 5
+                },
+                MultiRepair {
+                    edits: vec![Edit {
+                        index: 2,
+                        instruction: Instruction::NOP(-1)
+                    }],
+                    accumulator: 6
+                },
+            ],
+            repairs.repairs
+        );
+        assert!(!repairs.unique);
+    }
+
+    #[test]
+    fn test_repair_minimal_edit_acc() {
+        // the accumulator overflows before the loop, so no toggle alone repairs this
+        let program: Program = "
+            acc +9223372036854775807
+            acc +1
+            nop +2
+            jmp +0
+            acc -1
+        "
+        .parse()
+        .unwrap();
+        let options = RepairOptions {
+            max_edits: 2,
+            swap_acc: false,
+            edit_acc: false,
+        };
+        assert_eq!(0, repair_minimal(&program, options).repairs.len());
+        let options = RepairOptions {
+            edit_acc: true,
+            ..options
+        };
+        assert_eq!(
+            0,
+            repair_minimal(
+                &program,
+                RepairOptions {
+                    max_edits: 1,
+                    ..options
+                }
+            )
+            .repairs
+            .len()
+        );
+
+        let toggles = [
+            Edit {
+                index: 2,
+                instruction: Instruction::JMP(2),
+            },
+            Edit {
+                index: 3,
+                instruction: Instruction::NOP(0),
+            },
+        ];
+        let acc_edits = [
+            Edit {
+                index: 0,
+                instruction: Instruction::ACC(i64::MAX - 1),
+            },
+            Edit {
+                index: 1,
+                instruction: Instruction::ACC(0),
+            },
+        ];
+        let mut expected = vec![];
+        for &acc_edit in &acc_edits {
+            for &toggle in &toggles {
+                expected.push(MultiRepair {
+                    edits: vec![acc_edit, toggle],
+                    accumulator: i64::MAX - 1,
+                });
+            }
+        }
+        let repairs = repair_minimal(&program, options);
+        assert_eq!(expected, repairs.repairs);
+        assert!(!repairs.unique);
+    }
+}
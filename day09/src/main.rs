@@ -1,27 +1,45 @@
-use std::cmp::Ordering;
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, VecDeque};
+use std::env;
 use std::error::Error;
-use std::fmt;
+use std::fmt::{self, Debug, Display};
 use std::fs::File;
-use std::io::{self, BufRead, BufReader};
+use std::hash::Hash;
+use std::io::{self, BufRead, BufReader, Write};
 use std::num::ParseIntError;
+use std::ops::Range;
+use std::process;
+use std::str::FromStr;
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Debug)]
 enum PuzzleError {
-    IoError, // does not include the io::Error, otherwise we couldn’t derive Clone+Eq+PartialEq
+    IoError(io::Error),
     ParseIntError(ParseIntError),
+    Overflow(String), // the addition that overflowed
     NoBadSum,
     NoConsecutiveSum,
+    NumbersNotKept, // a range was asked of a scan that didn't keep the numbers
 }
 
 impl fmt::Display for PuzzleError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            PuzzleError::IoError(e) => write!(f, "IoError: {}", e),
+            PuzzleError::Overflow(addition) => write!(f, "Overflow: {}", addition),
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
-impl Error for PuzzleError {}
+impl Error for PuzzleError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PuzzleError::IoError(e) => Some(e),
+            PuzzleError::ParseIntError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl From<ParseIntError> for PuzzleError {
     fn from(e: ParseIntError) -> Self {
@@ -30,79 +48,571 @@ impl From<ParseIntError> for PuzzleError {
 }
 
 impl From<io::Error> for PuzzleError {
-    fn from(_e: io::Error) -> Self {
-        PuzzleError::IoError
+    fn from(e: io::Error) -> Self {
+        PuzzleError::IoError(e)
     }
 }
 
-fn parse_input<T: BufRead>(input: T) -> Result<Vec<u64>, PuzzleError> {
+// the integer types the puzzle can be solved with
+trait Number:
+    Copy + Debug + Display + Default + Eq + Hash + Ord + FromStr<Err = ParseIntError>
+{
+    fn checked_add(self, other: Self) -> Option<Self>;
+    fn overflowing_add(self, other: Self) -> (Self, bool);
+    fn overflowing_sub(self, other: Self) -> (Self, bool);
+
+    fn try_add(self, other: Self) -> Result<Self, PuzzleError> {
+        self.checked_add(other)
+            .ok_or_else(|| PuzzleError::Overflow(format!("{} + {}", self, other)))
+    }
+}
+
+macro_rules! impl_number {
+    ($($t:ty),*) => {
+        $(
+            impl Number for $t {
+                fn checked_add(self, other: Self) -> Option<Self> {
+                    <$t>::checked_add(self, other)
+                }
+
+                fn overflowing_add(self, other: Self) -> (Self, bool) {
+                    <$t>::overflowing_add(self, other)
+                }
+
+                fn overflowing_sub(self, other: Self) -> (Self, bool) {
+                    <$t>::overflowing_sub(self, other)
+                }
+            }
+        )*
+    };
+}
+
+impl_number!(u64, u128, i64);
+
+fn parse_input<T: Number, R: BufRead>(input: R) -> Result<Vec<T>, PuzzleError> {
     input
         .lines()
         .map(|line| line?.parse().map_err(PuzzleError::from))
         .collect()
 }
 
-fn find_bad_sum(nums: &[u64], preamble_length: usize) -> Option<u64> {
-    let mut buffer = VecDeque::<u64>::with_capacity(preamble_length);
-    let mut sums = HashMap::<u64, u64>::with_capacity(preamble_length * preamble_length);
-    let mut nums = nums.iter();
-    for num in nums.by_ref().take(preamble_length) {
-        for &num2 in &buffer {
-            *sums.entry(num + num2).or_default() += 1;
-        }
-        buffer.push_back(*num);
-    }
-    for num in nums {
-        if !sums.contains_key(num) {
-            return Some(*num);
-        }
-        let former_num = buffer.pop_front().expect("empty ring buffer");
-        for &num2 in &buffer {
-            match sums.entry(former_num + num2) {
-                Entry::Occupied(mut entry) => {
-                    let sum = entry.get_mut();
-                    *sum -= 1;
-                    if *sum == 0 {
-                        entry.remove();
-                    }
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Verdict {
+    Preamble,
+    Valid,   // a sum of the preceding numbers, according to the SumRule
+    Invalid, // not such a sum
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Checked<T> {
+    position: usize, // 0-based, in the whole stream
+    number: T,
+    verdict: Verdict,
+}
+
+// which numbers count as sums of the window
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct SumRule {
+    terms: usize, // how many window members are added up
+    reuse: bool,  // whether the same window member may be added more than once
+}
+
+impl Default for SumRule {
+    // the puzzle's rule: the sum of two different numbers from the window
+    fn default() -> Self {
+        SumRule {
+            terms: 2,
+            reuse: false,
+        }
+    }
+}
+
+const NUMBER_WORDS: [&str; 10] = [
+    "zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine",
+];
+
+impl fmt::Display for SumRule {
+    // what a valid number is the sum of, e.g. "two different numbers"
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match NUMBER_WORDS.get(self.terms) {
+            Some(word) => write!(f, "{}", word)?,
+            None => write!(f, "{}", self.terms)?,
+        }
+        match (self.terms, self.reuse) {
+            (1, _) => write!(f, " number"),
+            (_, true) => write!(f, " numbers (repeats allowed)"),
+            (_, false) => write!(f, " different numbers"),
+        }
+    }
+}
+
+/// Checks a stream of numbers one at a time,
+/// only keeping the last preamble_length numbers and their sums.
+struct XmasValidator<T> {
+    preamble_length: usize,
+    rule: SumRule,
+    buffer: VecDeque<T>,
+    // sums[j] counts the ways to add up j members of the buffer to each sum;
+    // they are updated like the coefficients of the polynomial ∏ (1 + num·t),
+    // or ∏ 1 / (1 - num·t) with reuse, when numbers enter and leave the buffer;
+    // the sums are wide, so a sum beyond T (which no number can be) doesn't wrap around to one
+    sums: Vec<HashMap<WideSum<T>, u64>>,
+    position: usize,
+}
+
+// add (or remove) num plus each sum of from to (or from) to
+fn shift_sums<T: Number>(
+    from: &HashMap<WideSum<T>, u64>,
+    to: &mut HashMap<WideSum<T>, u64>,
+    num: T,
+    remove: bool,
+) {
+    for (&sum, &count) in from {
+        if !remove {
+            *to.entry(sum.add(num)).or_default() += count;
+            continue;
+        }
+        match to.entry(sum.add(num)) {
+            Entry::Occupied(mut entry) => {
+                let sum = entry.get_mut();
+                *sum -= count;
+                if *sum == 0 {
+                    entry.remove();
                 }
-                _ => panic!("missing sum {}", former_num + num2),
             }
-            *sums.entry(num + num2).or_default() += 1;
+            Entry::Vacant(entry) => panic!("missing sum {:?}", entry.key()),
         }
-        buffer.push_back(*num);
     }
-    None
 }
 
-fn part1<T: BufRead>(input: T, preamble_length: usize) -> Result<u64, PuzzleError> {
-    let input = parse_input(input)?;
-    find_bad_sum(&input, preamble_length).ok_or(PuzzleError::NoBadSum)
+impl<T: Number> XmasValidator<T> {
+    fn new(preamble_length: usize) -> Self {
+        XmasValidator::with_rule(preamble_length, SumRule::default())
+    }
+
+    fn with_rule(preamble_length: usize, rule: SumRule) -> Self {
+        let mut sums = vec![HashMap::new(); rule.terms + 1];
+        sums[0].insert(WideSum::from(T::default()), 1); // the empty sum
+        XmasValidator {
+            preamble_length,
+            rule,
+            buffer: VecDeque::with_capacity(preamble_length),
+            sums,
+            position: 0,
+        }
+    }
+
+    /// Check the next number against the preceding ones, then add it to them
+    /// (even if it is invalid, so that every later number is checked against its actual predecessors).
+    fn push(&mut self, num: T) -> Checked<T> {
+        let checked = Checked {
+            position: self.position,
+            number: num,
+            verdict: self.verdict(num),
+        };
+        self.position += 1;
+        if self.preamble_length == 0 {
+            return checked;
+        }
+        if self.buffer.len() == self.preamble_length {
+            let former_num = self.buffer.pop_front().expect("empty ring buffer");
+            self.update_sums(former_num, true);
+        }
+        self.update_sums(num, false);
+        self.buffer.push_back(num);
+        checked
+    }
+
+    // what push would say about num, without pushing it
+    fn verdict(&self, num: T) -> Verdict {
+        if self.buffer.len() < self.preamble_length {
+            Verdict::Preamble
+        } else if self.sums[self.rule.terms].contains_key(&WideSum::from(num)) {
+            Verdict::Valid
+        } else {
+            Verdict::Invalid
+        }
+    }
+
+    fn update_sums(&mut self, num: T, remove: bool) {
+        // without reuse, adding num to sums[j] must only see sums[j - 1] without num;
+        // with reuse, it must see them with num (and the other way around for removing)
+        let ascending = self.rule.reuse != remove;
+        let terms = self.rule.terms;
+        for i in 0..terms {
+            let j = if ascending { i + 1 } else { terms - i };
+            let (smaller, larger) = self.sums.split_at_mut(j);
+            shift_sums(&smaller[j - 1], &mut larger[0], num, remove);
+        }
+    }
 }
 
-fn part2<T: BufRead>(input: T, preamble_length: usize) -> Result<u64, PuzzleError> {
-    let input = parse_input(input)?;
-    let bad_sum = find_bad_sum(&input, preamble_length).ok_or(PuzzleError::NoBadSum)?;
-    for upper_limit in 2..input.len() {
-        for lower_limit in (0..upper_limit - 1).rev() {
-            let range = &input[lower_limit..upper_limit + 1];
-            let sum = range.iter().sum();
-            match bad_sum.cmp(&sum) {
-                Ordering::Greater => continue, // grow range by decreasing lower_limit
-                Ordering::Equal => {
-                    return Ok(range.iter().min().expect("empty iterator")
-                        + range.iter().max().expect("empty iterator"))
-                }
-                Ordering::Less => break, // no point in growing range further
+/// Check every number of the input as it is read.
+fn validate<T, R>(
+    input: R,
+    mut validator: XmasValidator<T>,
+) -> impl Iterator<Item = Result<Checked<T>, PuzzleError>>
+where
+    T: Number,
+    R: BufRead,
+{
+    input
+        .lines()
+        .map(move |line| Ok(validator.push(line?.parse()?)))
+}
+
+/// What a single pass over the input found.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Scan<T> {
+    first_invalid: Option<Checked<T>>,
+    nums: Option<Vec<T>>, // all of them, if they were kept
+}
+
+impl<T: Number> Scan<T> {
+    /// Check the numbers of the input with the validator as they are read, until on_invalid
+    /// (which also gets the numbers the invalid one was checked against) returns false.
+    /// The rest of the input is only read if the numbers are kept,
+    /// since a range that sums to the invalid number can also come after it.
+    fn read<R, F>(
+        mut input: R,
+        validator: XmasValidator<T>,
+        keep: bool,
+        mut on_invalid: F,
+    ) -> Result<Self, PuzzleError>
+    where
+        R: BufRead,
+        F: FnMut(&Checked<T>, &[T]) -> Result<bool, PuzzleError>,
+    {
+        let preamble_length = validator.preamble_length;
+        let mut first_invalid = None;
+        let mut nums = Vec::new();
+        let mut window = VecDeque::with_capacity(preamble_length + 1);
+        for checked in validate(&mut input, validator) {
+            let checked = checked?;
+            if keep {
+                nums.push(checked.number);
+            }
+            if checked.verdict == Verdict::Invalid
+                && !on_invalid(&checked, window.make_contiguous())?
+            {
+                first_invalid = Some(checked);
+                break;
+            }
+            window.push_back(checked.number);
+            if window.len() > preamble_length {
+                window.pop_front();
+            }
+        }
+        if !keep {
+            return Ok(Scan {
+                first_invalid,
+                nums: None,
+            });
+        }
+        nums.extend(parse_input::<T, _>(input)?);
+        Ok(Scan {
+            first_invalid,
+            nums: Some(nums),
+        })
+    }
+
+    // the answer to part 1
+    fn bad_sum(&self) -> Result<T, PuzzleError> {
+        self.first_invalid
+            .map(|checked| checked.number)
+            .ok_or(PuzzleError::NoBadSum)
+    }
+
+    fn nums(&self) -> Result<&[T], PuzzleError> {
+        self.nums.as_deref().ok_or(PuzzleError::NumbersNotKept)
+    }
+
+    // the first range of the kept numbers that sums to the first invalid number
+    fn weak_range(&self) -> Result<Range<usize>, PuzzleError> {
+        contiguous_ranges(self.nums()?, self.bad_sum()?)
+            .next()
+            .ok_or(PuzzleError::NoConsecutiveSum)
+    }
+
+    // the answer to part 2
+    fn weakness(&self) -> Result<T, PuzzleError> {
+        let range = &self.nums()?[self.weak_range()?];
+        let min = *range.iter().min().expect("empty range");
+        let max = *range.iter().max().expect("empty range");
+        min.try_add(max)
+    }
+}
+
+fn part1<T: Number, R: BufRead>(input: R, preamble_length: usize) -> Result<T, PuzzleError> {
+    Scan::read(input, XmasValidator::new(preamble_length), false, |_, _| {
+        Ok(false)
+    })?
+    .bad_sum()
+}
+
+/// A sum that can't overflow: wraps times 2^(the bits of T), plus low.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+struct WideSum<T> {
+    wraps: i64,
+    low: T,
+}
+
+impl<T: Number> From<T> for WideSum<T> {
+    fn from(low: T) -> Self {
+        WideSum { wraps: 0, low }
+    }
+}
+
+impl<T: Number> WideSum<T> {
+    // past the maximum of T when adding a positive number, below its minimum for a negative one
+    fn add(self, num: T) -> Self {
+        let (low, overflowed) = self.low.overflowing_add(num);
+        let wraps = match (overflowed, num > T::default()) {
+            (false, _) => self.wraps,
+            (true, true) => self.wraps + 1,
+            (true, false) => self.wraps - 1,
+        };
+        WideSum { wraps, low }
+    }
+
+    fn sub(self, num: T) -> Self {
+        let (low, overflowed) = self.low.overflowing_sub(num);
+        let wraps = match (overflowed, num > T::default()) {
+            (false, _) => self.wraps,
+            (true, true) => self.wraps - 1,
+            (true, false) => self.wraps + 1,
+        };
+        WideSum { wraps, low }
+    }
+}
+
+/// All ranges of at least two consecutive numbers that sum to the target,
+/// by end and then from the shortest to the longest,
+/// found through the prefix sums: a range sums to the target
+/// if the prefix sum at its end minus the target is the prefix sum at its start.
+/// The prefix sums are wider than T, so that the numbers before a range can add up to anything.
+struct ContiguousRanges<'a, T> {
+    nums: &'a [T],
+    target: T,
+    prefix_sums: Vec<WideSum<T>>, // prefix_sums[i] is the sum of nums[..i]
+    // the indices with each prefix sum, only up to two before the current end
+    starts: HashMap<WideSum<T>, Vec<usize>>,
+    pending: Vec<Range<usize>>, // the remaining ranges with the current end, longest first
+}
+
+fn contiguous_ranges<T: Number>(nums: &[T], target: T) -> ContiguousRanges<'_, T> {
+    ContiguousRanges {
+        nums,
+        target,
+        prefix_sums: vec![WideSum::from(T::default())],
+        starts: HashMap::new(),
+        pending: Vec::new(),
+    }
+}
+
+impl<T: Number> Iterator for ContiguousRanges<'_, T> {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(range) = self.pending.pop() {
+                return Some(range);
+            }
+            let end = self.prefix_sums.len();
+            if end > self.nums.len() {
+                return None;
+            }
+            let sum = self.prefix_sums[end - 1].add(self.nums[end - 1]);
+            self.prefix_sums.push(sum);
+            if end >= 2 {
+                let start = end - 2; // the latest start that still leaves two numbers
+                self.starts
+                    .entry(self.prefix_sums[start])
+                    .or_default()
+                    .push(start);
+            }
+            if let Some(starts) = self.starts.get(&sum.sub(self.target)) {
+                self.pending = starts.iter().map(|&start| start..end).collect();
             }
         }
     }
-    Err(PuzzleError::NoConsecutiveSum)
+}
+
+fn part2<T: Number, R: BufRead>(input: R, preamble_length: usize) -> Result<T, PuzzleError> {
+    Scan::read(input, XmasValidator::new(preamble_length), true, |_, _| {
+        Ok(false)
+    })?
+    .weakness()
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Command {
+    Both,
+    Part1,
+    Part2,
+    Invalid, // every invalid number
+    Ranges,  // every range that sums to the first invalid number
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Options {
+    command: Command,
+    path: String, // - for standard input
+    preamble_length: usize,
+    rule: SumRule,
+    explain: bool,
+}
+
+const USAGE: &str =
+    "usage: day09 [1|2|both|invalid|ranges] [PATH|-] [--preamble N] [--terms K] [--reuse] [--explain]";
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options {
+        command: Command::Both,
+        path: "input".to_owned(),
+        preamble_length: 25,
+        rule: SumRule::default(),
+        explain: false,
+    };
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--preamble" => {
+                let preamble_length = args.next().ok_or(USAGE)?;
+                options.preamble_length = preamble_length
+                    .parse()
+                    .map_err(|e| format!("bad preamble length {}: {}", preamble_length, e))?;
+            }
+            "--terms" => {
+                let terms = args.next().ok_or(USAGE)?;
+                options.rule.terms = terms
+                    .parse()
+                    .map_err(|e| format!("bad number of terms {}: {}", terms, e))?;
+            }
+            "--reuse" => options.rule.reuse = true,
+            "--explain" => options.explain = true,
+            "--help" | "-h" => return Err(USAGE.to_owned()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}\n{}", arg, USAGE)),
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter().peekable();
+    let command = match positional.peek().map(String::as_str) {
+        Some("1") => Some(Command::Part1),
+        Some("2") => Some(Command::Part2),
+        Some("both") => Some(Command::Both),
+        Some("invalid") => Some(Command::Invalid),
+        Some("ranges") => Some(Command::Ranges),
+        _ => None,
+    };
+    if let Some(command) = command {
+        options.command = command;
+        positional.next();
+    }
+    if let Some(path) = positional.next() {
+        options.path = path;
+    }
+    match positional.next() {
+        Some(arg) => Err(format!("unexpected argument {}\n{}", arg, USAGE)),
+        None => Ok(options),
+    }
+}
+
+// why a number is invalid, given the rule and the numbers before it
+fn explain_invalid<W: Write>(
+    output: &mut W,
+    rule: SumRule,
+    checked: &Checked<u64>,
+    window: &[u64],
+) -> io::Result<()> {
+    let window: Vec<String> = window.iter().map(u64::to_string).collect();
+    writeln!(
+        output,
+        "{} at index {} is not the sum of {} among the {} before it: {}",
+        checked.number,
+        checked.position,
+        rule,
+        window.len(),
+        window.join(" ")
+    )
+}
+
+fn explain_range<W: Write>(output: &mut W, input: &[u64], range: Range<usize>) -> io::Result<()> {
+    let nums: Vec<String> = input[range.clone()].iter().map(u64::to_string).collect();
+    writeln!(output, "range {:?} sums to it: {}", range, nums.join(" + "))
+}
+
+/// Answer the command in a single pass over the input,
+/// which only goes beyond the first invalid number if part 2 or the ranges need it.
+fn run<R: BufRead, W: Write>(
+    options: &Options,
+    input: R,
+    output: &mut W,
+) -> Result<(), PuzzleError> {
+    let command = options.command;
+    let preamble_length = options.preamble_length;
+    // the puzzle's answers on their own are just what part1 and part2 find
+    if !options.explain && options.rule == SumRule::default() {
+        match command {
+            Command::Part1 => {
+                writeln!(output, "{}", part1::<u64, _>(input, preamble_length)?)?;
+                return Ok(());
+            }
+            Command::Part2 => {
+                writeln!(output, "{}", part2::<u64, _>(input, preamble_length)?)?;
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+
+    let validator = XmasValidator::with_rule(preamble_length, options.rule);
+    let keep = matches!(command, Command::Both | Command::Part2 | Command::Ranges);
+    let scan = Scan::<u64>::read(input, validator, keep, |checked, window| {
+        if options.explain {
+            explain_invalid(output, options.rule, checked, window)?;
+        } else if command == Command::Invalid {
+            writeln!(output, "{}: {}", checked.position, checked.number)?;
+        }
+        Ok(command == Command::Invalid)
+    })?;
+    if command == Command::Invalid {
+        return Ok(());
+    }
+
+    if let Command::Part1 | Command::Both = command {
+        writeln!(output, "{}", scan.bad_sum()?)?;
+    }
+    if command == Command::Ranges {
+        for range in contiguous_ranges(scan.nums()?, scan.bad_sum()?) {
+            writeln!(output, "{:?}", range)?;
+        }
+    }
+    if let Command::Part2 | Command::Both = command {
+        if options.explain {
+            explain_range(output, scan.nums()?, scan.weak_range()?)?;
+        }
+        writeln!(output, "{}", scan.weakness()?)?;
+    }
+    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    println!("{}", part1(BufReader::new(File::open("input")?), 25)?);
-    println!("{}", part2(BufReader::new(File::open("input")?), 25)?);
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(2);
+        }
+    };
+    let stdout = io::stdout();
+    if options.path == "-" {
+        let stdin = io::stdin();
+        run(&options, stdin.lock(), &mut stdout.lock())?;
+    } else {
+        let file = BufReader::new(File::open(&options.path)?);
+        run(&options, file, &mut stdout.lock())?;
+    }
     Ok(())
 }
 
@@ -110,6 +620,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 mod tests {
     use super::*;
     use pretty_assertions::assert_eq;
+    use std::io::BufReader;
 
     const SAMPLE_INPUT: &str = "\
 35
@@ -138,22 +649,389 @@ mod tests {
     #[test]
     fn test_part1() {
         assert_eq!(
-            Ok(127),
+            127u64,
             part1(
                 BufReader::new(SAMPLE_INPUT.as_bytes()),
                 SAMPLE_PREAMBLE_LENGTH
             )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let checked: Vec<Checked<u64>> = validate(
+            BufReader::new(SAMPLE_INPUT.as_bytes()),
+            XmasValidator::new(SAMPLE_PREAMBLE_LENGTH),
+        )
+        .collect::<Result<_, _>>()
+        .unwrap();
+        assert_eq!(20, checked.len());
+        assert!(checked[..5]
+            .iter()
+            .all(|checked| checked.verdict == Verdict::Preamble));
+        assert_eq!(
+            vec![Checked {
+                position: 14,
+                number: 127,
+                verdict: Verdict::Invalid
+            }],
+            checked
+                .into_iter()
+                .filter(|checked| checked.verdict == Verdict::Invalid)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_validator_reports_every_invalid_number() {
+        let mut validator = XmasValidator::<u64>::new(2);
+        let verdicts: Vec<Verdict> = [1, 2, 3, 4, 7, 100, 107, 1]
+            .iter()
+            .map(|&num| validator.push(num).verdict)
+            .collect();
+        assert_eq!(
+            vec![
+                Verdict::Preamble,
+                Verdict::Preamble,
+                Verdict::Valid,
+                Verdict::Invalid,
+                Verdict::Valid,
+                Verdict::Invalid,
+                Verdict::Valid,
+                Verdict::Invalid,
+            ],
+            verdicts
         );
+
+        let mut validator = XmasValidator::<u64>::new(0);
+        assert_eq!(Verdict::Invalid, validator.push(0).verdict);
+        assert_eq!(Verdict::Invalid, validator.push(0).verdict);
+    }
+
+    #[test]
+    fn test_validate_long_stream() {
+        // 0 is always the sum of two earlier 0s
+        let input = "0\n".repeat(100_000);
+        let not_valid = validate(
+            BufReader::new(input.as_bytes()),
+            XmasValidator::<u64>::new(25),
+        )
+        .filter(|checked| checked.as_ref().unwrap().verdict != Verdict::Valid)
+        .count();
+        assert_eq!(25, not_valid);
+        assert!(matches!(
+            part1::<u64, _>(BufReader::new("1\n2\nx\n".as_bytes()), 5),
+            Err(PuzzleError::ParseIntError(_))
+        ));
     }
 
     #[test]
     fn test_part2() {
         assert_eq!(
-            Ok(62),
+            62u64,
             part2(
                 BufReader::new(SAMPLE_INPUT.as_bytes()),
                 SAMPLE_PREAMBLE_LENGTH
             )
+            .unwrap()
         );
     }
+
+    fn ranges<T: Number>(nums: &[T], target: T) -> Vec<Range<usize>> {
+        contiguous_ranges(nums, target).collect()
+    }
+
+    #[test]
+    fn test_contiguous_ranges() {
+        let sample: Vec<u64> = parse_input(BufReader::new(SAMPLE_INPUT.as_bytes())).unwrap();
+        assert_eq!(vec![2..6], ranges(&sample, 127));
+        assert_eq!(vec![0..2, 0..3, 2..4, 1..4], ranges(&[1u64, 0, 0, 1], 1));
+        // a single number is not a range
+        assert_eq!(0, ranges(&[3u64, 5, 3], 5).len());
+        // sums beyond u64 don't overflow
+        assert_eq!(vec![1..3], ranges(&[u64::MAX, u64::MAX, 0], u64::MAX));
+        assert_eq!(
+            vec![1..3],
+            ranges(&[u64::MAX as u128, u64::MAX as u128, 0], u64::MAX as u128)
+        );
+        assert!(matches!(
+            part2::<u64, _>(BufReader::new("1\n2\n3\n10\n".as_bytes()), 2),
+            Err(PuzzleError::NoConsecutiveSum)
+        ));
+    }
+
+    #[test]
+    fn test_contiguous_ranges_match_brute_force() {
+        let nums: Vec<u64> = (0..200u64).map(|i| i * i * 7 % 13).collect();
+        for target in 0..60 {
+            let mut brute_force = Vec::new();
+            for end in 2..=nums.len() {
+                for start in (0..end - 1).rev() {
+                    if nums[start..end].iter().sum::<u64>() == target {
+                        brute_force.push(start..end);
+                    }
+                }
+            }
+            assert_eq!(brute_force, ranges(&nums, target));
+        }
+    }
+
+    // whether num is the sum of terms of the window, the slow way
+    fn is_sum(window: &[i64], num: i64, terms: usize, reuse: bool) -> bool {
+        match (terms, window.split_first()) {
+            (0, _) => num == 0,
+            (_, None) => false,
+            (_, Some((&first, rest))) => {
+                is_sum(rest, num, terms, reuse)
+                    || is_sum(
+                        if reuse { window } else { rest },
+                        num - first,
+                        terms - 1,
+                        reuse,
+                    )
+            }
+        }
+    }
+
+    #[test]
+    fn test_sum_rules() {
+        // a deterministic mix of small positive and negative numbers, with repetitions
+        let nums: Vec<i64> = (0..300i64)
+            .map(|i| (i * i * 31 + 7 * i) % 41 - 20)
+            .collect();
+        for &(preamble_length, terms) in &[(5, 1), (5, 2), (6, 3), (8, 4), (3, 5), (4, 0)] {
+            for &reuse in &[false, true] {
+                let rule = SumRule { terms, reuse };
+                let mut validator = XmasValidator::with_rule(preamble_length, rule);
+                for (position, &num) in nums.iter().enumerate() {
+                    let expected = if position < preamble_length {
+                        Verdict::Preamble
+                    } else if is_sum(
+                        &nums[position - preamble_length..position],
+                        num,
+                        terms,
+                        reuse,
+                    ) {
+                        Verdict::Valid
+                    } else {
+                        Verdict::Invalid
+                    };
+                    assert_eq!(
+                        expected,
+                        validator.push(num).verdict,
+                        "{:?} at {}",
+                        rule,
+                        position
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_number_types() {
+        // the sample scaled beyond u64, and mirrored into the negative numbers
+        let wide: String = SAMPLE_INPUT
+            .lines()
+            .map(|line| format!("{}\n", line.parse::<u128>().unwrap() << 64))
+            .collect();
+        assert_eq!(
+            127u128 << 64,
+            part1(BufReader::new(wide.as_bytes()), SAMPLE_PREAMBLE_LENGTH).unwrap()
+        );
+        assert_eq!(
+            62u128 << 64,
+            part2(BufReader::new(wide.as_bytes()), SAMPLE_PREAMBLE_LENGTH).unwrap()
+        );
+        let negative: String = SAMPLE_INPUT
+            .lines()
+            .map(|line| format!("-{}\n", line))
+            .collect();
+        assert_eq!(
+            -127i64,
+            part1(BufReader::new(negative.as_bytes()), SAMPLE_PREAMBLE_LENGTH).unwrap()
+        );
+        assert_eq!(
+            -62i64,
+            part2(BufReader::new(negative.as_bytes()), SAMPLE_PREAMBLE_LENGTH).unwrap()
+        );
+        // a longer range can have a smaller sum, which a two-pointer search would miss
+        assert_eq!(vec![0..3, 2..5], ranges(&[2i64, -1, 2, -1, 2], 3));
+    }
+
+    #[test]
+    fn test_overflow() {
+        let max = u64::MAX;
+        // sums beyond the maximum are no number, rather than wrapping around to a small one
+        let mut validator = XmasValidator::<u64>::new(2);
+        let verdicts: Vec<Verdict> = [max - 1, 1, max, 0, max, max, max - 1]
+            .iter()
+            .map(|&num| validator.push(num).verdict)
+            .collect();
+        assert_eq!(
+            vec![
+                Verdict::Preamble,
+                Verdict::Preamble,
+                Verdict::Valid,
+                Verdict::Invalid,
+                Verdict::Valid,
+                Verdict::Valid,
+                Verdict::Invalid,
+            ],
+            verdicts
+        );
+        // and can come back into range with a negative number
+        let mut validator = XmasValidator::with_rule(
+            3,
+            SumRule {
+                terms: 3,
+                reuse: false,
+            },
+        );
+        for &num in &[i64::MAX, 1, -5] {
+            validator.push(num);
+        }
+        assert_eq!(Verdict::Valid, validator.push(i64::MAX - 4).verdict);
+        // ranges are found before, after and across sums beyond the maximum
+        assert_eq!(0, ranges(&[1, max, 3], 5).len());
+        assert_eq!(vec![1..3], ranges(&[1, 2, 3, max], 5));
+        assert_eq!(vec![1..3], ranges(&[max, 2, 3], 5));
+        assert_eq!(vec![2..4], ranges(&[i64::MIN, -1, 3, 2], 5));
+        assert_eq!(vec![0..3], ranges(&[i64::MIN, -1, 1], i64::MIN));
+        // the numbers after a huge one are checked all the same, whatever the type
+        let input = format!("{}\n1\n2\n", max);
+        assert_eq!(2u64, part1(BufReader::new(input.as_bytes()), 2).unwrap());
+        assert_eq!(2u128, part1(BufReader::new(input.as_bytes()), 2).unwrap());
+    }
+
+    #[test]
+    fn test_io_error() {
+        let error = part1::<u64, _>(BufReader::new(&b"1\n\xff\n"[..]), 1).unwrap_err();
+        assert!(matches!(error, PuzzleError::IoError(_)));
+        assert_eq!(
+            "IoError: stream did not contain valid UTF-8",
+            error.to_string()
+        );
+        assert!(error.source().is_some());
+    }
+
+    fn run_cli(args: &[&str], input: &str) -> String {
+        let options = parse_args(args.iter().map(|&arg| arg.to_owned())).unwrap();
+        let mut output = Vec::new();
+        run(&options, input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            Ok(Options {
+                command: Command::Both,
+                path: "input".to_owned(),
+                preamble_length: 25,
+                rule: SumRule::default(),
+                explain: false,
+            }),
+            parse_args(std::iter::empty())
+        );
+        assert_eq!(
+            Ok(Options {
+                command: Command::Part2,
+                path: "-".to_owned(),
+                preamble_length: 5,
+                rule: SumRule {
+                    terms: 3,
+                    reuse: true
+                },
+                explain: true,
+            }),
+            parse_args(
+                [
+                    "--explain",
+                    "2",
+                    "--preamble",
+                    "5",
+                    "--terms",
+                    "3",
+                    "--reuse",
+                    "-"
+                ]
+                .iter()
+                .map(|&arg| arg.to_owned())
+            )
+        );
+        assert_eq!(
+            "data/day09.txt",
+            parse_args(std::iter::once("data/day09.txt".to_owned()))
+                .unwrap()
+                .path
+        );
+        for args in &[
+            &["--preamble"][..],
+            &["--preamble", "x"],
+            &["--terms", "-1"],
+            &["--frobnicate"],
+            &["1", "a", "b"],
+        ] {
+            assert!(parse_args(args.iter().map(|&arg| arg.to_owned())).is_err());
+        }
+    }
+
+    #[test]
+    fn test_cli() {
+        assert_eq!("127\n62\n", run_cli(&["--preamble", "5"], SAMPLE_INPUT));
+        assert_eq!("127\n", run_cli(&["1", "--preamble", "5"], SAMPLE_INPUT));
+        // part 1 doesn't read beyond the first invalid number
+        let garbled = format!("{}not a number\n", SAMPLE_INPUT);
+        assert_eq!("127\n", run_cli(&["1", "--preamble", "5"], &garbled));
+        assert_eq!(
+            "\
+127 at index 14 is not the sum of two different numbers among the 5 before it: 95 102 117 150 182
+range 2..6 sums to it: 15 + 25 + 47 + 40
+62
+",
+            run_cli(&["2", "--preamble", "5", "--explain"], SAMPLE_INPUT)
+        );
+        assert_eq!(
+            "14: 127\n",
+            run_cli(&["invalid", "--preamble", "5"], SAMPLE_INPUT)
+        );
+        assert_eq!(
+            "2..6\n",
+            run_cli(&["ranges", "--preamble", "5"], SAMPLE_INPUT)
+        );
+        // other rules are explained as they are
+        assert_eq!(
+            "\
+40 at index 5 is not the sum of three different numbers among the 5 before it: 35 20 15 25 47
+40
+",
+            run_cli(
+                &["1", "--preamble", "5", "--terms", "3", "--explain"],
+                SAMPLE_INPUT
+            )
+        );
+        assert_eq!(
+            "\
+127 at index 14 is not the sum of two numbers (repeats allowed) among the 5 before it: 95 102 117 150 182
+127
+",
+            run_cli(&["1", "--preamble", "5", "--reuse", "--explain"], SAMPLE_INPUT)
+        );
+    }
+
+    #[test]
+    fn test_scan() {
+        let stop = |_: &Checked<u64>, _: &[u64]| Ok(false);
+        let validator = || XmasValidator::new(SAMPLE_PREAMBLE_LENGTH);
+        let scan = Scan::read(SAMPLE_INPUT.as_bytes(), validator(), true, stop).unwrap();
+        assert_eq!(127, scan.bad_sum().unwrap());
+        assert_eq!(2..6, scan.weak_range().unwrap());
+        assert_eq!(62, scan.weakness().unwrap());
+        // without the numbers, there is no telling where the range is
+        let scan = Scan::read(SAMPLE_INPUT.as_bytes(), validator(), false, stop).unwrap();
+        assert_eq!(127, scan.bad_sum().unwrap());
+        assert!(matches!(scan.weakness(), Err(PuzzleError::NumbersNotKept)));
+    }
 }
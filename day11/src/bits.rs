@@ -0,0 +1,167 @@
+// Bitmaps of cells, 64 to a word, with bit i of word w standing for cell 64 * w + i.
+
+use std::iter;
+
+pub const WORD_BITS: usize = 64;
+
+pub fn words(bits: usize) -> usize {
+    bits.div_ceil(WORD_BITS)
+}
+
+pub fn get(bitmap: &[u64], index: usize) -> bool {
+    bitmap[index / WORD_BITS] >> (index % WORD_BITS) & 1 == 1
+}
+
+pub fn set(bitmap: &mut [u64], index: usize, value: bool) {
+    let mask = 1 << (index % WORD_BITS);
+    if value {
+        bitmap[index / WORD_BITS] |= mask;
+    } else {
+        bitmap[index / WORD_BITS] &= !mask;
+    }
+}
+
+pub fn flip(bitmap: &mut [u64], index: usize) {
+    bitmap[index / WORD_BITS] ^= 1 << (index % WORD_BITS);
+}
+
+pub fn count(bitmap: &[u64]) -> usize {
+    bitmap.iter().map(|word| word.count_ones() as usize).sum()
+}
+
+/// The indices of the cells whose bits are set.
+pub fn ones(bitmap: &[u64]) -> impl Iterator<Item = usize> + '_ {
+    ones_of_words(bitmap.iter().copied())
+}
+
+/// The indices of the cells whose bits differ between a and b.
+pub fn differences<'a>(a: &'a [u64], b: &'a [u64]) -> impl Iterator<Item = usize> + 'a {
+    ones_of_words(a.iter().zip(b).map(|(a, b)| a ^ b))
+}
+
+fn ones_of_words(words: impl Iterator<Item = u64>) -> impl Iterator<Item = usize> {
+    words.enumerate().flat_map(|(w, mut word)| {
+        iter::from_fn(move || {
+            if word == 0 {
+                return None;
+            }
+            let bit = word.trailing_zeros() as usize;
+            word &= word - 1;
+            Some(w * WORD_BITS + bit)
+        })
+    })
+}
+
+/// Every cell's western neighbor, i.e. word shifted one cell to the east,
+/// with the last cell of the previous word moving in.
+pub fn west(previous: u64, word: u64) -> u64 {
+    word << 1 | previous >> (WORD_BITS - 1)
+}
+
+/// Every cell's eastern neighbor, i.e. word shifted one cell to the west,
+/// with the first cell of the next word moving in.
+pub fn east(word: u64, next: u64) -> u64 {
+    word >> 1 | next << (WORD_BITS - 1)
+}
+
+/// Counters from 0 to 15 for 64 cells at once:
+/// bit i of planes[j] is bit j of the count for cell i.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Counts {
+    planes: [u64; 4],
+}
+
+impl Counts {
+    /// Add one to the count of every cell whose bit is set in mask.
+    pub fn add(&mut self, mask: u64) {
+        let mut carry = mask;
+        for plane in self.planes.iter_mut() {
+            let next_carry = *plane & carry;
+            *plane ^= carry;
+            carry = next_carry;
+        }
+    }
+
+    /// The cells whose count is exactly n.
+    pub fn eq(&self, n: usize) -> u64 {
+        if n >= 1 << self.planes.len() {
+            return 0;
+        }
+        self.planes
+            .iter()
+            .enumerate()
+            .fold(!0, |mask, (bit, &plane)| {
+                mask & if n >> bit & 1 == 1 { plane } else { !plane }
+            })
+    }
+
+    /// The cells whose count is at most n.
+    pub fn at_most(&self, n: usize) -> u64 {
+        (0..=n.min(15)).fold(0, |mask, n| mask | self.eq(n))
+    }
+
+    /// The cells whose count is at least n.
+    pub fn at_least(&self, n: usize) -> u64 {
+        (n..16).fold(0, |mask, n| mask | self.eq(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_get_set_count() {
+        let mut bitmap = vec![0; words(130)];
+        assert_eq!(3, bitmap.len());
+        set(&mut bitmap, 0, true);
+        set(&mut bitmap, 64, true);
+        set(&mut bitmap, 129, true);
+        set(&mut bitmap, 129, true);
+        assert_eq!(vec![1, 1, 2], bitmap);
+        assert!(get(&bitmap, 129));
+        assert!(!get(&bitmap, 128));
+        set(&mut bitmap, 0, false);
+        assert_eq!(2, count(&bitmap));
+        flip(&mut bitmap, 64);
+        flip(&mut bitmap, 65);
+        assert_eq!(vec![0, 2, 2], bitmap);
+    }
+
+    #[test]
+    fn test_ones_and_differences() {
+        let a = vec![0b1010, 0, 1 << 63];
+        let b = vec![0b0110, 1, 1 << 63];
+        assert_eq!(vec![1, 3, 191], ones(&a).collect::<Vec<_>>());
+        assert_eq!(vec![2, 3, 64], differences(&a, &b).collect::<Vec<_>>());
+        assert_eq!(0, differences(&a, &a).count());
+    }
+
+    #[test]
+    fn test_shifts() {
+        assert_eq!(0b1010, west(0, 0b0101));
+        assert_eq!(1, west(1 << 63, 0));
+        assert_eq!(0b0010, east(0b0101, 0));
+        assert_eq!(1 << 63, east(0, 1));
+    }
+
+    #[test]
+    fn test_counts() {
+        let mut counts = Counts::default();
+        // cell i counts up to i, but no further than 8
+        for n in 1..=8 {
+            counts.add(!0 << n);
+        }
+        assert_eq!(1, counts.eq(0));
+        assert_eq!(1 << 4, counts.eq(4));
+        assert_eq!(!0 << 8, counts.eq(8));
+        assert_eq!(0, counts.eq(9));
+        assert_eq!(0, counts.eq(16));
+        assert_eq!(0b11111, counts.at_most(4));
+        assert_eq!(!0 << 5, counts.at_least(5));
+        assert_eq!(!0, counts.at_most(99));
+        assert_eq!(0, counts.at_least(9));
+        assert_eq!(!0, counts.at_least(0));
+    }
+}
@@ -0,0 +1,177 @@
+use crate::{bits, Cell, Grid};
+use std::io::{self, Write};
+
+/// What happened to every seat during a simulation:
+/// how often it flipped between empty and occupied, and in which round it last did,
+/// and how many seats changed in every round.
+#[derive(Debug)]
+pub struct History {
+    pub grid: Grid,    // at the end of the simulation
+    flips: Vec<usize>, // by index into the bitmaps of the grid
+    last_changed: Vec<Option<usize>>,
+    changes: Vec<usize>, // by round, starting with the first
+}
+
+impl History {
+    pub fn new(grid: &Grid) -> History {
+        let len = grid.floor.len() * bits::WORD_BITS;
+        History {
+            grid: grid.with_occupied(grid.occupied.clone()),
+            flips: vec![0; len],
+            last_changed: vec![None; len],
+            changes: Vec::new(),
+        }
+    }
+
+    /// Note that the changed seats changed in round, which comes right after the last one.
+    pub fn record(&mut self, round: usize, changed: &[usize]) {
+        for &index in changed {
+            self.flips[index] += 1;
+            self.last_changed[index] = Some(round);
+        }
+        self.changes.push(changed.len());
+    }
+
+    /// How many seats changed in round 1, 2, and so on.
+    pub fn changes(&self) -> &[usize] {
+        &self.changes
+    }
+
+    // the seats, row by row, with their index
+    fn seats(&self) -> impl Iterator<Item = (isize, isize, usize)> + '_ {
+        let grid = &self.grid;
+        (0..grid.height)
+            .flat_map(move |y| (0..grid.width).map(move |x| (x, y, grid.index(x, y).unwrap())))
+            .filter(move |&(_x, _y, index)| !bits::get(&grid.floor, index))
+    }
+
+    /// One line per seat, with its position, how often it flipped,
+    /// the round it last changed in (empty if it never did) and whether it ended up occupied.
+    pub fn write_csv<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        writeln!(output, "x,y,flips,last_changed,final_state")?;
+        for (x, y, index) in self.seats() {
+            let last_changed = match self.last_changed[index] {
+                Some(round) => round.to_string(),
+                None => String::new(),
+            };
+            let state = match self.grid.cell_at(index) {
+                Cell::OccupiedSeat => "occupied",
+                _ => "empty",
+            };
+            writeln!(
+                output,
+                "{},{},{},{},{}",
+                x, y, self.flips[index], last_changed, state
+            )?;
+        }
+        Ok(())
+    }
+
+    /// A grayscale image (binary PGM) with one pixel per cell:
+    /// floor and walls are black, and seats range from dark gray (never flipped) to white (flipped most often).
+    pub fn write_pgm<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        let max_flips = self
+            .seats()
+            .map(|(_x, _y, index)| self.flips[index])
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        write!(
+            output,
+            "P5\n{} {}\n255\n",
+            self.grid.width, self.grid.height
+        )?;
+        let mut row = Vec::with_capacity(self.grid.width as usize);
+        for y in 0..self.grid.height {
+            row.clear();
+            for x in 0..self.grid.width {
+                let index = self.grid.index(x, y).unwrap();
+                row.push(match self.grid.cell_at(index) {
+                    Cell::Floor | Cell::Wall => 0,
+                    _ => (55 + 200 * self.flips[index] / max_flips) as u8,
+                });
+            }
+            output.write_all(&row)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Neighborhood, Outcome, SeatingRules, SimulationOptions};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_history() {
+        // everyone sits down, then everyone is next to someone and leaves,
+        // except for the seat next to nobody
+        let restless = SeatingRules {
+            neighborhood: Neighborhood::Adjacent,
+            occupy_threshold: 0,
+            vacate_threshold: 1,
+        };
+        let grid: crate::Grid = "LL.#\nL...\n".parse().unwrap();
+        let (history, outcome) =
+            grid.fixpoint_with_history(&restless, &SimulationOptions::default());
+        assert_eq!(
+            Outcome::Cycle {
+                start: 0,
+                period: 2
+            },
+            outcome
+        );
+        assert_eq!("LL.#\nL...\n", history.grid.to_string());
+        assert_eq!(&[3, 3], history.changes());
+
+        let mut csv = Vec::new();
+        history.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "\
+x,y,flips,last_changed,final_state
+0,0,2,2,empty
+1,0,2,2,empty
+3,0,0,,occupied
+0,1,2,2,empty
+"
+        );
+
+        let mut pgm = Vec::new();
+        history.write_pgm(&mut pgm).unwrap();
+        let mut expected = b"P5\n4 2\n255\n".to_vec();
+        expected.extend_from_slice(&[255, 255, 0, 55, 255, 0, 0, 0]);
+        assert_eq!(expected, pgm);
+    }
+
+    #[test]
+    fn test_history_of_example() {
+        let grid: crate::Grid = "\
+L.LL.LL.LL
+LLLLLLL.LL
+L.L.L..L..
+LLLL.LL.LL
+L.LL.LL.LL
+L.LLLLL.LL
+..L.L.....
+LLLLLLLLLL
+L.LLLLLL.L
+L.LLLLL.LL
+"
+        .parse()
+        .unwrap();
+        let (history, outcome) =
+            grid.fixpoint_with_history(&SeatingRules::PART1, &SimulationOptions::default());
+        assert_eq!(Outcome::Converged { rounds: 5 }, outcome);
+        assert_eq!(grid.fixpoint_part1(), history.grid);
+        assert_eq!(&[71, 51, 31, 21, 7], history.changes());
+        let flips = |x, y| history.flips[history.grid.index(x, y).unwrap()];
+        let last_changed = |x, y| history.last_changed[history.grid.index(x, y).unwrap()];
+        // the corner sits down in the first round and stays
+        assert_eq!((1, Some(1)), (flips(0, 0), last_changed(0, 0)));
+        // this one changes in every round, the last time in the last one
+        assert_eq!((5, Some(5)), (flips(2, 2), last_changed(2, 2)));
+        // floor never changes
+        assert_eq!((0, None), (flips(1, 0), last_changed(1, 0)));
+    }
+}
@@ -1,9 +1,21 @@
-use im::{vector, Vector};
+mod bits;
+mod history;
+
+use bits::Counts;
+use history::History;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::env;
 use std::error::Error;
 use std::fmt;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::iter;
+use std::mem;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 enum ParseError {
@@ -11,6 +23,8 @@ enum ParseError {
     BadCharacter(char),
     BadWidth(usize, usize), // expected, actual
     WidthTooLarge,
+    BadNeighborhood,
+    BadTopology,
 }
 
 impl fmt::Display for ParseError {
@@ -26,6 +40,7 @@ enum Cell {
     Floor,
     EmptySeat,
     OccupiedSeat,
+    Wall,
 }
 
 impl FromStr for Cell {
@@ -39,6 +54,7 @@ impl FromStr for Cell {
             Some('.') => Ok(Cell::Floor),
             Some('L') => Ok(Cell::EmptySeat),
             Some('#') => Ok(Cell::OccupiedSeat),
+            Some('|') => Ok(Cell::Wall),
             Some(c) => Err(ParseError::BadCharacter(c)),
             None => Err(ParseError::BadLength(0)),
         }
@@ -51,133 +67,903 @@ impl fmt::Display for Cell {
             Cell::Floor => write!(f, "."),
             Cell::EmptySeat => write!(f, "L"),
             Cell::OccupiedSeat => write!(f, "#"),
+            Cell::Wall => write!(f, "|"),
+        }
+    }
+}
+
+#[rustfmt::skip]
+const DIRECTIONS: [(isize, isize); 8] = [
+    (-1, -1), (0, -1), (1, -1),
+    (-1, 0), /*(0, 0),*/ (1, 0),
+    (-1, 1), (0, 1), (1, 1),
+];
+
+/// Which seats count as the neighbors of a seat.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Neighborhood {
+    /// The seats in the eight cells around it (part 1).
+    Adjacent,
+    /// The first seat in each of the eight directions, however far away (part 2).
+    LineOfSight,
+    /// The first seat in each direction, if it is at most this many cells away.
+    LineOfSightWithin(usize),
+}
+
+impl Neighborhood {
+    // the cells between a seat and its neighbor are all floor, so being adjacent means being
+    // the first seat in a direction one cell away
+    fn max_distance(self) -> Option<usize> {
+        match self {
+            Neighborhood::Adjacent => Some(1),
+            Neighborhood::LineOfSight => None,
+            Neighborhood::LineOfSightWithin(distance) => Some(distance),
         }
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+impl FromStr for Neighborhood {
+    type Err = ParseError;
+
+    // adjacent, sight, or sight:N for at most N cells away
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "adjacent" => Ok(Neighborhood::Adjacent),
+            "sight" => Ok(Neighborhood::LineOfSight),
+            _ => match s.strip_prefix("sight:").map(str::parse) {
+                Some(Ok(distance)) => Ok(Neighborhood::LineOfSightWithin(distance)),
+                _ => Err(ParseError::BadNeighborhood),
+            },
+        }
+    }
+}
+
+/// How the cells of a grid are connected to each other.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Topology {
+    /// A rectangle with nothing beyond its edges (the puzzle's).
+    Bounded,
+    /// A rectangle whose edges wrap around: leaving it on one side comes back in on the other.
+    Torus,
+    /// A bounded rectangle of hexagons, with every odd row shifted half a cell to the east,
+    /// so every cell has six neighbors along three lines.
+    Hex,
+    /// A bounded rectangle in which walls block line of sight;
+    /// in the other topologies, walls are just floor.
+    Walled,
+}
+
+// for Hex, a direction with dy != 0 goes to the neighbor half a cell west or east in that row
+#[rustfmt::skip]
+const HEX_DIRECTIONS: [(isize, isize); 6] = [
+    (-1, -1), (1, -1),
+    (-1, 0), (1, 0),
+    (-1, 1), (1, 1),
+];
+
+impl Topology {
+    fn directions(self) -> &'static [(isize, isize)] {
+        match self {
+            Topology::Hex => &HEX_DIRECTIONS,
+            _ => &DIRECTIONS,
+        }
+    }
+}
+
+impl FromStr for Topology {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bounded" => Ok(Topology::Bounded),
+            "torus" => Ok(Topology::Torus),
+            "hex" => Ok(Topology::Hex),
+            "walled" => Ok(Topology::Walled),
+            _ => Err(ParseError::BadTopology),
+        }
+    }
+}
+
+/// How a round changes the seats, depending on how many of their neighbors are occupied.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct SeatingRules {
+    neighborhood: Neighborhood,
+    // an empty seat becomes occupied if at most this many of its neighbors are occupied
+    occupy_threshold: usize,
+    // an occupied seat becomes empty if at least this many of its neighbors are occupied
+    vacate_threshold: usize,
+}
+
+impl SeatingRules {
+    const PART1: SeatingRules = SeatingRules {
+        neighborhood: Neighborhood::Adjacent,
+        occupy_threshold: 0,
+        vacate_threshold: 4,
+    };
+
+    const PART2: SeatingRules = SeatingRules {
+        neighborhood: Neighborhood::LineOfSight,
+        occupy_threshold: 0,
+        vacate_threshold: 5,
+    };
+
+    fn apply(&self, cell: Cell, occupied_neighbors: usize) -> Cell {
+        match cell {
+            Cell::EmptySeat if occupied_neighbors <= self.occupy_threshold => Cell::OccupiedSeat,
+            Cell::OccupiedSeat if occupied_neighbors >= self.vacate_threshold => Cell::EmptySeat,
+            _ => cell,
+        }
+    }
+}
+
+/// How a simulation ended.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Outcome {
+    /// Nothing changed anymore after this many rounds.
+    Converged { rounds: usize },
+    /// The grid after start rounds comes back every period rounds (and period > 1).
+    Cycle { start: usize, period: usize },
+    /// Still changing when the round cap was reached, after this many rounds.
+    RoundLimit { rounds: usize },
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Converged { rounds } => write!(f, "converged after {} rounds", rounds),
+            Outcome::Cycle { start, period } => {
+                write!(f, "cycles with period {} from round {} on", period, start)
+            }
+            Outcome::RoundLimit { rounds } => write!(f, "still changing after {} rounds", rounds),
+        }
+    }
+}
+
+/// How to run a simulation, apart from the rules it follows.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct SimulationOptions {
+    // stop after this many rounds, even if the grid is still changing
+    max_rounds: Option<usize>,
+    // split every round into this many bands of rows, each computed by its own thread
+    threads: usize,
+    // once few seats change, only look at those and their neighbors instead of every seat
+    incremental: bool,
+}
+
+impl Default for SimulationOptions {
+    fn default() -> Self {
+        SimulationOptions {
+            max_rounds: None,
+            threads: 1,
+            incremental: true,
+        }
+    }
+}
+
+// called with the number of every round and the seats that changed in it
+type RoundCallback<'a> = dyn FnMut(usize, &[usize]) + 'a;
+
+// below one changed seat in this many cells, a round only looks at the changes and their neighbors
+const DIRTY_RATIO: usize = 16;
+
+/// For every cell, its neighbors (as indices into the bitmaps of the grid):
+/// the first seat in each direction that has one within the maximum distance, if any.
+/// Floor never changes, so this only depends on the floor plan, not on who sits where.
+#[derive(Debug)]
+struct Neighbors {
+    starts: Vec<usize>, // the neighbors of cell i are seats[starts[i]..starts[i + 1]]
+    seats: Vec<usize>,
+}
+
+impl Neighbors {
+    fn new(grid: &Grid, max_distance: Option<usize>) -> Neighbors {
+        let len = grid.floor.len() * bits::WORD_BITS;
+        let in_range = |seat: Option<(usize, usize)>| match (seat, max_distance) {
+            (Some((_seat, distance)), Some(max_distance)) if distance > max_distance => None,
+            (Some((seat, _distance)), _) => Some(seat),
+            (None, _) => None,
+        };
+        // counting first and filling in afterwards keeps only one direction in memory at a time
+        let mut counts = vec![0; len];
+        for &direction in grid.topology.directions() {
+            let nearest = Self::nearest_seats(grid, direction);
+            for (count, seat) in counts.iter_mut().zip(nearest) {
+                *count += in_range(seat).is_some() as usize;
+            }
+        }
+        let mut starts = Vec::with_capacity(len + 1);
+        let mut total = 0;
+        for count in counts {
+            starts.push(total);
+            total += count;
+        }
+        starts.push(total);
+        let mut seats = vec![0; total];
+        let mut next = starts.clone();
+        for &direction in grid.topology.directions() {
+            let nearest = Self::nearest_seats(grid, direction);
+            for (index, seat) in nearest.into_iter().enumerate() {
+                if let Some(seat) = in_range(seat) {
+                    seats[next[index]] = seat;
+                    next[index] += 1;
+                }
+            }
+        }
+        Neighbors { starts, seats }
+    }
+
+    // for every cell, the first seat beyond it in the direction and how far away it is;
+    // every cell's answer follows from the next cell's, so each line of cells is only walked once
+    // and then filled in backwards, instead of walking every ray
+    fn nearest_seats(grid: &Grid, direction: (isize, isize)) -> Vec<Option<(usize, usize)>> {
+        let len = grid.floor.len() * bits::WORD_BITS;
+        let mut nearest = vec![None; len];
+        let mut done = vec![false; len];
+        let transparent = |index| bits::get(&grid.floor, index) && !grid.blocks_sight(index);
+        // the cells of a line, each with the index of the cell after it
+        let mut line = Vec::new();
+        for y in 0..grid.height {
+            for x in 0..grid.width {
+                let start = grid.index(x, y).unwrap();
+                if done[start] {
+                    continue;
+                }
+                done[start] = true;
+                let (mut x, mut y, mut index) = (x, y, start);
+                // on a torus, a line can come back to its start, which is already done
+                while let Some((next_x, next_y)) = grid.next(x, y, direction) {
+                    let next = grid.index(next_x, next_y).unwrap();
+                    line.push((index, Some(next)));
+                    if done[next] || !transparent(next) {
+                        break;
+                    }
+                    done[next] = true;
+                    (x, y, index) = (next_x, next_y, next);
+                }
+                if line.last().map(|&(last, _next)| last) != Some(index) {
+                    line.push((index, None));
+                }
+                for (index, next) in line.drain(..).rev() {
+                    let seat = match next {
+                        // a line of floor that comes back to where it started sees no seat
+                        Some(next) if transparent(next) => {
+                            nearest[next].map(|(seat, distance)| (seat, distance + 1))
+                        }
+                        Some(next) if !bits::get(&grid.floor, next) => Some((next, 1)),
+                        _ => None,
+                    };
+                    // and a seat doesn't see itself around a torus
+                    nearest[index] = seat.filter(|&(seat, _distance)| seat != index);
+                }
+            }
+        }
+        nearest
+    }
+
+    fn of(&self, index: usize) -> &[usize] {
+        &self.seats[self.starts[index]..self.starts[index + 1]]
+    }
+
+    fn count_occupied(&self, occupied: &[u64], index: usize) -> usize {
+        self.of(index)
+            .iter()
+            .filter(|&&seat| bits::get(occupied, seat))
+            .count()
+    }
+}
+
 struct Grid {
     // width and height can’t actually be negative,
     // but we want to be able to index -1 easily
     width: isize,
     height: isize,
-    cells: Vector<Cell>,
+    // the cells as two bitmaps, row by row, with every row starting at a new word;
+    // the bits after the end of a row are floor, so they never count as seats
+    words_per_row: usize,
+    floor: Vec<u64>,
+    occupied: Vec<u64>,
+    // walls are floor as far as the seats are concerned, this only tells them apart
+    walls: Vec<u64>,
+    topology: Topology,
+    // by maximum distance, computed on first use
+    // and shared with every grid that rounds produce from this one
+    neighbors: Arc<Mutex<HashMap<Option<usize>, Arc<Neighbors>>>>,
+}
+
+// the neighbors are derived from the floor, so they don’t take part in comparisons
+impl PartialEq for Grid {
+    fn eq(&self, other: &Grid) -> bool {
+        self.width == other.width
+            && self.height == other.height
+            && self.floor == other.floor
+            && self.occupied == other.occupied
+            && self.walls == other.walls
+            && self.topology == other.topology
+    }
+}
+
+impl Eq for Grid {}
+
+impl fmt::Debug for Grid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Grid")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("topology", &self.topology)
+            .field("cells", &self.to_string())
+            .finish_non_exhaustive()
+    }
 }
 
 impl Grid {
-    fn cell(&self, x: isize, y: isize) -> Option<Cell> {
+    // cells holds the rows one after the other
+    fn from_cells(width: isize, height: isize, cells: &[Cell]) -> Grid {
+        let words_per_row = bits::words(usize::try_from(width).unwrap());
+        let len = words_per_row * usize::try_from(height).unwrap();
+        let mut grid = Grid {
+            width,
+            height,
+            words_per_row,
+            floor: vec![!0; len],
+            occupied: vec![0; len],
+            walls: vec![0; len],
+            topology: Topology::Bounded,
+            neighbors: Arc::default(),
+        };
+        for y in 0..height {
+            for x in 0..width {
+                let cell = cells[usize::try_from(y * width + x).unwrap()];
+                let index = grid.index(x, y).unwrap();
+                bits::set(
+                    &mut grid.floor,
+                    index,
+                    cell == Cell::Floor || cell == Cell::Wall,
+                );
+                bits::set(&mut grid.occupied, index, cell == Cell::OccupiedSeat);
+                bits::set(&mut grid.walls, index, cell == Cell::Wall);
+            }
+        }
+        grid
+    }
+
+    /// The same cells, connected differently.
+    fn with_topology(self, topology: Topology) -> Grid {
+        Grid {
+            topology,
+            neighbors: Arc::default(),
+            ..self
+        }
+    }
+
+    fn index(&self, x: isize, y: isize) -> Option<usize> {
         if 0 <= x && x < self.width && 0 <= y && y < self.height {
-            Some(self.cells[usize::try_from(y * self.width + x).unwrap()])
+            Some(usize::try_from(y).unwrap() * self.words_per_row * bits::WORD_BITS + x as usize)
         } else {
             None
         }
     }
 
+    fn cell_at(&self, index: usize) -> Cell {
+        if bits::get(&self.walls, index) {
+            Cell::Wall
+        } else if bits::get(&self.floor, index) {
+            Cell::Floor
+        } else if bits::get(&self.occupied, index) {
+            Cell::OccupiedSeat
+        } else {
+            Cell::EmptySeat
+        }
+    }
+
+    fn cell(&self, x: isize, y: isize) -> Option<Cell> {
+        self.index(x, y).map(|index| self.cell_at(index))
+    }
+
+    // the position one step from (x, y) in direction, if it is still on the grid
+    fn next(&self, x: isize, y: isize, (dx, dy): (isize, isize)) -> Option<(isize, isize)> {
+        let x = match self.topology {
+            // the odd rows are shifted east, so their neighbors above and below are at x and x + 1,
+            // and those of the even rows at x - 1 and x
+            Topology::Hex if dy != 0 => x + y.rem_euclid(2) - (dx < 0) as isize,
+            _ => x + dx,
+        };
+        let y = y + dy;
+        match self.topology {
+            Topology::Torus => Some((x.rem_euclid(self.width), y.rem_euclid(self.height))),
+            _ => self.index(x, y).map(|_index| (x, y)),
+        }
+    }
+
+    fn blocks_sight(&self, index: usize) -> bool {
+        self.topology == Topology::Walled && bits::get(&self.walls, index)
+    }
+
     fn count_eq(&self, cell: Cell) -> usize {
-        self.cells.iter().filter(|&c| *c == cell).count()
+        let cells = usize::try_from(self.width * self.height).unwrap();
+        let row_padding = self.words_per_row * bits::WORD_BITS - self.width as usize;
+        let walls = bits::count(&self.walls);
+        let floor = bits::count(&self.floor) - row_padding * self.height as usize - walls;
+        let occupied = bits::count(&self.occupied);
+        match cell {
+            Cell::Floor => floor,
+            Cell::EmptySeat => cells - floor - walls - occupied,
+            Cell::OccupiedSeat => occupied,
+            Cell::Wall => walls,
+        }
     }
 
-    fn count_eq_neighbors(&self, cell: Cell, x: isize, y: isize) -> usize {
-        #[rustfmt::skip]
-        let deltas = vec![
-            (-1, -1), (0, -1), (1, -1),
-            (-1, 0), /*(0, 0),*/ (1, 0),
-            (-1, 1), (0, 1), (1, 1),
-        ];
-        deltas
-            .iter()
-            .map(|&(dx, dy)| self.cell(x + dx, y + dy))
-            .filter(|&neighbor| neighbor == Some(cell))
-            .count()
+    fn neighbors(&self, neighborhood: Neighborhood) -> Arc<Neighbors> {
+        let max_distance = neighborhood.max_distance();
+        let mut neighbors = self.neighbors.lock().unwrap();
+        let neighbors = neighbors
+            .entry(max_distance)
+            .or_insert_with(|| Arc::new(Neighbors::new(self, max_distance)));
+        Arc::clone(neighbors)
     }
 
-    fn count_eq_visible(&self, cell: Cell, x: isize, y: isize) -> usize {
-        #[rustfmt::skip]
-        let deltas = vec![
-            (-1, -1), (0, -1), (1, -1),
-            (-1, 0), /*(0, 0),*/ (1, 0),
-            (-1, 1), (0, 1), (1, 1),
-        ];
-        deltas
-            .iter()
-            .map(|&(dx, dy)| {
-                for i in 1.. {
-                    match self.cell(x + i * dx, y + i * dy) {
-                        Some(Cell::Floor) => continue,
-                        Some(visible) => return visible,
-                        None => return Cell::Floor,
-                    }
+    // the neighbors to look up one by one, or None to count adjacent seats with shifts,
+    // which only line up the neighbors of a plain rectangle
+    fn neighbors_for(&self, rules: &SeatingRules) -> Option<Arc<Neighbors>> {
+        match (rules.neighborhood, self.topology) {
+            (Neighborhood::Adjacent, Topology::Bounded) => None,
+            (Neighborhood::Adjacent, Topology::Walled) => None,
+            (neighborhood, _topology) => Some(self.neighbors(neighborhood)),
+        }
+    }
+
+    // one round from occupied into new_occupied;
+    // every row only depends on the previous round, so bands of rows can be computed in parallel
+    fn step(
+        &self,
+        rules: &SeatingRules,
+        threads: usize,
+        occupied: &[u64],
+        new_occupied: &mut [u64],
+    ) {
+        let neighbors = self.neighbors_for(rules);
+        let neighbors = neighbors.as_deref();
+        let rows = self.height as usize;
+        let band_rows = rows.div_ceil(threads.max(1)).max(1);
+        let band_words = band_rows * self.words_per_row;
+        if band_rows >= rows || band_words == 0 {
+            return self.step_band(rules, neighbors, occupied, 0, new_occupied);
+        }
+        thread::scope(|scope| {
+            let bands: Vec<_> = new_occupied
+                .chunks_mut(band_words)
+                .enumerate()
+                .map(|(band, new_band)| {
+                    scope.spawn(move || {
+                        self.step_band(rules, neighbors, occupied, band * band_rows, new_band)
+                    })
+                })
+                .collect();
+            for band in bands {
+                band.join().unwrap();
+            }
+        })
+    }
+
+    // one round for the rows from first_row on that new_band has room for
+    fn step_band(
+        &self,
+        rules: &SeatingRules,
+        neighbors: Option<&Neighbors>,
+        occupied: &[u64],
+        first_row: usize,
+        new_band: &mut [u64],
+    ) {
+        match neighbors {
+            None => self.step_adjacent(rules, occupied, first_row, new_band),
+            Some(neighbors) => self.step_neighbors(rules, neighbors, occupied, first_row, new_band),
+        }
+    }
+
+    // counts the adjacent seats of 64 cells at once:
+    // shifting the rows by one cell lines every cell up with its neighbors
+    fn step_adjacent(
+        &self,
+        rules: &SeatingRules,
+        occupied: &[u64],
+        first_row: usize,
+        new_band: &mut [u64],
+    ) {
+        let words = self.words_per_row;
+        let outside = vec![0; words];
+        for (band_row, new_row) in new_band.chunks_mut(words).enumerate() {
+            let y = first_row + band_row;
+            let rows = self.rows_around(occupied, &outside, y);
+            for (w, new_word) in new_row.iter_mut().enumerate() {
+                *new_word = self.adjacent_word(rules, rows, y, w);
+            }
+        }
+    }
+
+    // the rows above, at and below row y, with outside standing in beyond the edges
+    fn rows_around<'a>(&self, occupied: &'a [u64], outside: &'a [u64], y: usize) -> [&'a [u64]; 3] {
+        let words = self.words_per_row;
+        let row = |y: isize| {
+            if 0 <= y && y < self.height {
+                &occupied[y as usize * words..][..words]
+            } else {
+                outside
+            }
+        };
+        let y = y as isize;
+        [row(y - 1), row(y), row(y + 1)]
+    }
+
+    // the next round of word w in row y, from rows_around that row
+    fn adjacent_word(&self, rules: &SeatingRules, rows: [&[u64]; 3], y: usize, w: usize) -> u64 {
+        let mut counts = Counts::default();
+        for (dy, row) in rows.iter().enumerate() {
+            let previous = if w > 0 { row[w - 1] } else { 0 };
+            let next = row.get(w + 1).copied().unwrap_or(0);
+            counts.add(bits::west(previous, row[w]));
+            counts.add(bits::east(row[w], next));
+            if dy != 1 {
+                counts.add(row[w]);
+            }
+        }
+        let seats = !self.floor[y * self.words_per_row + w];
+        let current = rows[1][w];
+        let sitting_down = seats & !current & counts.at_most(rules.occupy_threshold);
+        let leaving = current & counts.at_least(rules.vacate_threshold);
+        (current | sitting_down) & !leaving
+    }
+
+    // looks up the neighbors of each seat one by one
+    fn step_neighbors(
+        &self,
+        rules: &SeatingRules,
+        neighbors: &Neighbors,
+        occupied: &[u64],
+        first_row: usize,
+        new_band: &mut [u64],
+    ) {
+        let first_word = first_row * self.words_per_row;
+        for (band_word, new_word) in new_band.iter_mut().enumerate() {
+            let w = first_word + band_word;
+            let current = occupied[w];
+            let mut word = current;
+            let mut seats = !self.floor[w];
+            while seats != 0 {
+                let bit = seats.trailing_zeros() as usize;
+                seats &= seats - 1;
+                let cell = if current >> bit & 1 == 1 {
+                    Cell::OccupiedSeat
+                } else {
+                    Cell::EmptySeat
+                };
+                let count = neighbors.count_occupied(occupied, w * bits::WORD_BITS + bit);
+                if rules.apply(cell, count) == Cell::OccupiedSeat {
+                    word |= 1 << bit;
+                } else {
+                    word &= !(1 << bit);
                 }
-                Cell::Floor
-            })
-            .filter(|&neighbor| neighbor == cell)
-            .count()
+            }
+            *new_word = word;
+        }
     }
 
-    fn round_part1(&self) -> Grid {
-        let mut new_cells = self.cells.clone();
-        for x in 0..self.width {
-            for y in 0..self.height {
-                let cell = self.cell(x, y).unwrap();
-                if cell == Cell::Floor {
-                    continue;
+    // one round that only looks at the seats that changed in the last round and their neighbors,
+    // since those are the only ones whose neighbors changed (a seat is the neighbor of its
+    // neighbors); the seats that change now go into new_changed, but occupied stays as it is;
+    // dirty marks what was already looked at, and must be all clear, as it is again at the end
+    fn step_dirty(
+        &self,
+        rules: &SeatingRules,
+        neighbors: Option<&Neighbors>,
+        occupied: &[u64],
+        changed: &[usize],
+        dirty: &mut [u64],
+        new_changed: &mut Vec<usize>,
+    ) {
+        match neighbors {
+            None => self.step_dirty_adjacent(rules, occupied, changed, dirty, new_changed),
+            Some(neighbors) => {
+                Self::step_dirty_neighbors(rules, neighbors, occupied, changed, dirty, new_changed)
+            }
+        }
+    }
+
+    // recomputes whole words with shifts, like step_adjacent: the word of a changed seat,
+    // the ones above and below it, and the ones beside those if it is at the end of its word
+    fn step_dirty_adjacent(
+        &self,
+        rules: &SeatingRules,
+        occupied: &[u64],
+        changed: &[usize],
+        dirty: &mut [u64],
+        new_changed: &mut Vec<usize>,
+    ) {
+        let words = self.words_per_row;
+        let last_row = self.height as usize - 1;
+        let mut candidates = Vec::new();
+        for &seat in changed {
+            let (word, bit) = (seat / bits::WORD_BITS, seat % bits::WORD_BITS);
+            let (y, w) = (word / words, word % words);
+            let first_w = if bit == 0 { w.saturating_sub(1) } else { w };
+            let last_w = if bit == bits::WORD_BITS - 1 {
+                (w + 1).min(words - 1)
+            } else {
+                w
+            };
+            for y in y.saturating_sub(1)..=(y + 1).min(last_row) {
+                for w in first_w..=last_w {
+                    let candidate = y * words + w;
+                    if !bits::get(dirty, candidate) {
+                        bits::set(dirty, candidate, true);
+                        candidates.push(candidate);
+                    }
                 }
-                let occupied = self.count_eq_neighbors(Cell::OccupiedSeat, x, y);
-                if cell == Cell::EmptySeat && occupied == 0 {
-                    new_cells[usize::try_from(y * self.width + x).unwrap()] = Cell::OccupiedSeat;
-                } else if cell == Cell::OccupiedSeat && occupied >= 4 {
-                    new_cells[usize::try_from(y * self.width + x).unwrap()] = Cell::EmptySeat;
+            }
+        }
+        let outside = vec![0; words];
+        for candidate in candidates {
+            bits::set(dirty, candidate, false);
+            let (y, w) = (candidate / words, candidate % words);
+            let rows = self.rows_around(occupied, &outside, y);
+            let new_word = self.adjacent_word(rules, rows, y, w);
+            let first_seat = candidate * bits::WORD_BITS;
+            new_changed.extend(
+                bits::differences(&[occupied[candidate]], &[new_word]).map(|bit| first_seat + bit),
+            );
+        }
+    }
+
+    // looks up the neighbors of the changed seats, and theirs, one by one
+    fn step_dirty_neighbors(
+        rules: &SeatingRules,
+        neighbors: &Neighbors,
+        occupied: &[u64],
+        changed: &[usize],
+        dirty: &mut [u64],
+        new_changed: &mut Vec<usize>,
+    ) {
+        let mut candidates = Vec::new();
+        for &seat in changed {
+            for &candidate in iter::once(&seat).chain(neighbors.of(seat)) {
+                if !bits::get(dirty, candidate) {
+                    bits::set(dirty, candidate, true);
+                    candidates.push(candidate);
                 }
             }
         }
+        for candidate in candidates {
+            bits::set(dirty, candidate, false);
+            let cell = if bits::get(occupied, candidate) {
+                Cell::OccupiedSeat
+            } else {
+                Cell::EmptySeat
+            };
+            if rules.apply(cell, neighbors.count_occupied(occupied, candidate)) != cell {
+                new_changed.push(candidate);
+            }
+        }
+    }
+
+    fn with_occupied(&self, occupied: Vec<u64>) -> Grid {
         Grid {
             width: self.width,
             height: self.height,
-            cells: new_cells,
+            words_per_row: self.words_per_row,
+            floor: self.floor.clone(),
+            occupied,
+            walls: self.walls.clone(),
+            topology: self.topology,
+            neighbors: Arc::clone(&self.neighbors),
         }
     }
 
-    fn fixpoint_part1(self) -> Grid {
-        let mut grid = self;
-        let mut next_grid = grid.round_part1();
-        while grid != next_grid {
-            grid = next_grid;
-            next_grid = grid.round_part1();
+    // a round can update this hash from just the seats that changed,
+    // as it's the xor of a pseudorandom key for every occupied seat
+    fn seat_key(index: usize) -> u64 {
+        // splitmix64's finalizer
+        let mut key = (index as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        key = (key ^ key >> 30).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        key = (key ^ key >> 27).wrapping_mul(0x94d0_49bb_1331_11eb);
+        key ^ key >> 31
+    }
+
+    fn hash_seats(occupied: &[u64]) -> u64 {
+        bits::ones(occupied).fold(0, |hash, seat| hash ^ Grid::seat_key(seat))
+    }
+
+    // whether occupied comes back after exactly this many rounds
+    fn returns_after(
+        &self,
+        rules: &SeatingRules,
+        threads: usize,
+        occupied: &[u64],
+        rounds: usize,
+    ) -> bool {
+        let mut current = occupied.to_vec();
+        let mut next = occupied.to_vec();
+        for _ in 0..rounds {
+            self.step(rules, threads, &current, &mut next);
+            mem::swap(&mut current, &mut next);
         }
-        grid
+        current == occupied
     }
 
-    fn round_part2(&self) -> Grid {
-        let mut new_cells = self.cells.clone();
-        for x in 0..self.width {
-            for y in 0..self.height {
-                let cell = self.cell(x, y).unwrap();
-                if cell == Cell::Floor {
-                    continue;
+    /// Run rounds until nothing changes anymore, an earlier grid comes back,
+    /// or (if there is a cap) the grid still changes after options.max_rounds rounds,
+    /// and return the last grid and which of these happened.
+    fn fixpoint(&self, rules: &SeatingRules, options: &SimulationOptions) -> (Grid, Outcome) {
+        self.run(rules, options, &mut |_round, _changed| {})
+    }
+
+    /// Like fixpoint, but also record what happened to every seat on the way.
+    fn fixpoint_with_history(
+        &self,
+        rules: &SeatingRules,
+        options: &SimulationOptions,
+    ) -> (History, Outcome) {
+        let mut history = History::new(self);
+        let (grid, outcome) = self.run(rules, options, &mut |round, changed| {
+            history.record(round, changed)
+        });
+        history.grid = grid;
+        (history, outcome)
+    }
+
+    fn run(
+        &self,
+        rules: &SeatingRules,
+        options: &SimulationOptions,
+        on_round: &mut RoundCallback,
+    ) -> (Grid, Outcome) {
+        // full rounds swap between two bitmaps instead of allocating new ones,
+        // and rounds that only look at the changes update occupied in place
+        let mut occupied = self.occupied.clone();
+        let mut new_occupied = occupied.clone();
+        let mut changed = Vec::new();
+        let mut new_changed = Vec::new();
+        let mut dirty = vec![0; occupied.len()];
+        let cells = usize::try_from(self.width * self.height).unwrap();
+        // only looked up once a round is incremental, since it may need to be built
+        let mut neighbors = None;
+        // the first round after which each grid was seen, by hash;
+        // only the hashes are kept, so a repeat is confirmed by running one more period
+        let mut hash = Grid::hash_seats(&occupied);
+        let mut seen = HashMap::new();
+        seen.insert(hash, 0);
+        let mut rounds = 0;
+        let outcome = loop {
+            // before the first round, every seat counts as changed
+            new_changed.clear();
+            if options.incremental && rounds > 0 && changed.len() * DIRTY_RATIO < cells {
+                let neighbors = neighbors.get_or_insert_with(|| self.neighbors_for(rules));
+                self.step_dirty(
+                    rules,
+                    neighbors.as_deref(),
+                    &occupied,
+                    &changed,
+                    &mut dirty,
+                    &mut new_changed,
+                );
+                for &seat in &new_changed {
+                    bits::flip(&mut occupied, seat);
                 }
-                let occupied = self.count_eq_visible(Cell::OccupiedSeat, x, y);
-                if cell == Cell::EmptySeat && occupied == 0 {
-                    new_cells[usize::try_from(y * self.width + x).unwrap()] = Cell::OccupiedSeat;
-                } else if cell == Cell::OccupiedSeat && occupied >= 5 {
-                    new_cells[usize::try_from(y * self.width + x).unwrap()] = Cell::EmptySeat;
+            } else {
+                self.step(rules, options.threads, &occupied, &mut new_occupied);
+                new_changed.extend(bits::differences(&occupied, &new_occupied));
+                mem::swap(&mut occupied, &mut new_occupied);
+            }
+            if new_changed.is_empty() {
+                break Outcome::Converged { rounds };
+            }
+            // only a round that changes something goes over the cap, so undo it
+            if options.max_rounds == Some(rounds) {
+                for &seat in &new_changed {
+                    bits::flip(&mut occupied, seat);
                 }
+                break Outcome::RoundLimit { rounds };
             }
-        }
-        Grid {
-            width: self.width,
-            height: self.height,
-            cells: new_cells,
-        }
+            rounds += 1;
+            mem::swap(&mut changed, &mut new_changed);
+            on_round(rounds, &changed);
+            for &seat in &changed {
+                hash ^= Grid::seat_key(seat);
+            }
+            match seen.entry(hash) {
+                Entry::Occupied(mut entry) => {
+                    let start = *entry.get();
+                    let period = rounds - start;
+                    if self.returns_after(rules, options.threads, &occupied, period) {
+                        break Outcome::Cycle { start, period };
+                    }
+                    // a hash collision: remember the newer grid instead
+                    entry.insert(rounds);
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(rounds);
+                }
+            }
+        };
+        (self.with_occupied(occupied), outcome)
+    }
+
+    // the puzzle's rules always settle down
+    fn fixpoint_part1(self) -> Grid {
+        self.fixpoint(&SeatingRules::PART1, &SimulationOptions::default())
+            .0
     }
 
     fn fixpoint_part2(self) -> Grid {
-        let mut grid = self;
-        let mut next_grid = grid.round_part2();
-        while grid != next_grid {
-            grid = next_grid;
-            next_grid = grid.round_part2();
+        self.fixpoint(&SeatingRules::PART2, &SimulationOptions::default())
+            .0
+    }
+
+    // the cells around this one in the directions of the topology, wherever it leads,
+    // but never the cell itself (which a torus one cell wide or high leads back to);
+    // seats are looked up in the same table as the rounds use
+    fn count_eq_neighbors(&self, cell: Cell, x: isize, y: isize) -> usize {
+        let index = self.index(x, y).unwrap();
+        match cell {
+            Cell::EmptySeat | Cell::OccupiedSeat => {
+                self.count_eq_among(Neighborhood::Adjacent, cell, index)
+            }
+            Cell::Floor | Cell::Wall => self
+                .topology
+                .directions()
+                .iter()
+                .filter_map(|&direction| self.next(x, y, direction))
+                .filter(|&(next_x, next_y)| (next_x, next_y) != (x, y))
+                .filter(|&(next_x, next_y)| self.cell(next_x, next_y) == Some(cell))
+                .count(),
         }
-        grid
+    }
+
+    fn count_eq_visible(&self, cell: Cell, x: isize, y: isize) -> usize {
+        let index = self.index(x, y).unwrap();
+        self.count_eq_among(Neighborhood::LineOfSight, cell, index)
+    }
+
+    fn count_eq_among(&self, neighborhood: Neighborhood, cell: Cell, index: usize) -> usize {
+        self.neighbors(neighborhood)
+            .of(index)
+            .iter()
+            .filter(|&&seat| self.cell_at(seat) == cell)
+            .count()
+    }
+
+    fn round(&self, rules: &SeatingRules) -> Grid {
+        let mut new_occupied = self.occupied.clone();
+        self.step(rules, 1, &self.occupied, &mut new_occupied);
+        self.with_occupied(new_occupied)
+    }
+
+    // one round seat by seat, as the puzzle describes it,
+    // with the number of occupied neighbors of the seat at (x, y) from occupied_neighbors;
+    // much slower than round, which counts whole rows at once, but easy to check against the puzzle
+    fn round_by(
+        &self,
+        rules: &SeatingRules,
+        occupied_neighbors: impl Fn(isize, isize) -> usize,
+    ) -> Grid {
+        let mut new_occupied = self.occupied.clone();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let index = self.index(x, y).unwrap();
+                let cell = rules.apply(self.cell_at(index), occupied_neighbors(x, y));
+                bits::set(&mut new_occupied, index, cell == Cell::OccupiedSeat);
+            }
+        }
+        self.with_occupied(new_occupied)
+    }
+
+    fn round_part1(&self) -> Grid {
+        self.round_by(&SeatingRules::PART1, |x, y| {
+            self.count_eq_neighbors(Cell::OccupiedSeat, x, y)
+        })
+    }
+
+    fn round_part2(&self) -> Grid {
+        self.round_by(&SeatingRules::PART2, |x, y| {
+            self.count_eq_visible(Cell::OccupiedSeat, x, y)
+        })
     }
 }
 
@@ -187,7 +973,7 @@ impl FromStr for Grid {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut width = 0;
         let mut height = 0;
-        let mut cells = vector![];
+        let mut cells = Vec::new();
         for line in s.lines() {
             if width == 0 {
                 width = line.len();
@@ -196,14 +982,11 @@ impl FromStr for Grid {
             }
             height += 1;
             for c in line.chars() {
-                cells.push_back(c.to_string().parse()?);
+                cells.push(c.to_string().parse()?);
             }
         }
-        Ok(Grid {
-            width: isize::try_from(width).map_err(|_| ParseError::WidthTooLarge)?,
-            height,
-            cells,
-        })
+        let width = isize::try_from(width).map_err(|_| ParseError::WidthTooLarge)?;
+        Ok(Grid::from_cells(width, height, &cells))
     }
 }
 
@@ -213,7 +996,7 @@ impl fmt::Display for Grid {
             for x in 0..self.width {
                 write!(f, "{}", self.cell(x, y).unwrap())?;
             }
-            writeln!(f, "")?;
+            writeln!(f)?;
         }
         Ok(())
     }
@@ -231,10 +1014,129 @@ fn part2(input: &str) -> Result<usize, ParseError> {
     Ok(fix.count_eq(Cell::OccupiedSeat))
 }
 
+// the occupied seats at the end of a simulation with any other rules,
+// e.g. to sweep over their parameters, and how it ended
+fn simulate(
+    input: &str,
+    topology: Topology,
+    rules: &SeatingRules,
+    options: &SimulationOptions,
+) -> Result<(usize, Outcome), ParseError> {
+    let grid = input.parse::<Grid>()?.with_topology(topology);
+    let (last, outcome) = grid.fixpoint(rules, options);
+    Ok((last.count_eq(Cell::OccupiedSeat), outcome))
+}
+
+const USAGE: &str = "usage: day11 [NEIGHBORHOOD OCCUPY_THRESHOLD VACATE_THRESHOLD [MAX_ROUNDS] \
+                     [--topology TOPOLOGY] [--threads N] [--csv FILE] [--heatmap FILE] [--changes]] [--print]";
+
+// prints the grid and then the grid after every round, like the puzzle's examples,
+// until nothing changes anymore or (if there is a cap) max_rounds rounds have run
+fn print_rounds(mut grid: Grid, max_rounds: Option<usize>, round: impl Fn(&Grid) -> Grid) {
+    print!("{}", grid);
+    let mut rounds = 0;
+    while max_rounds != Some(rounds) {
+        let next = round(&grid);
+        if next == grid {
+            break;
+        }
+        println!();
+        print!("{}", next);
+        grid = next;
+        rounds += 1;
+    }
+    println!();
+}
+
+// removes `name VALUE` from the arguments, returning the value
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, &'static str> {
+    match args.iter().position(|arg| arg == name) {
+        Some(position) if position + 1 < args.len() => {
+            let value = args.remove(position + 1);
+            args.remove(position);
+            Ok(Some(value))
+        }
+        Some(_position) => Err(USAGE),
+        None => Ok(None),
+    }
+}
+
+// removes name from the arguments, returning whether it was there
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != name);
+    args.len() < len
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let input = fs::read_to_string("input")?;
-    println!("{}", part1(&input)?);
-    println!("{}", part2(&input)?);
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut options = SimulationOptions::default();
+    if let Some(threads) = take_option(&mut args, "--threads")? {
+        options.threads = threads.parse()?;
+    }
+    let topology = take_option(&mut args, "--topology")?;
+    let csv = take_option(&mut args, "--csv")?;
+    let heatmap = take_option(&mut args, "--heatmap")?;
+    let changes = take_flag(&mut args, "--changes");
+    let print = take_flag(&mut args, "--print");
+    match args.as_slice() {
+        [] if topology.is_none() && csv.is_none() && heatmap.is_none() && !changes => {
+            if print {
+                print_rounds(input.parse()?, None, Grid::round_part1);
+                print_rounds(input.parse()?, None, Grid::round_part2);
+            }
+            println!("{}", part1(&input)?);
+            println!("{}", part2(&input)?);
+        }
+        [neighborhood, occupy_threshold, vacate_threshold, rest @ ..] if rest.len() <= 1 => {
+            let rules = SeatingRules {
+                neighborhood: neighborhood.parse()?,
+                occupy_threshold: occupy_threshold.parse()?,
+                vacate_threshold: vacate_threshold.parse()?,
+            };
+            if let Some(max_rounds) = rest.first() {
+                options.max_rounds = Some(max_rounds.parse()?);
+            }
+            let topology = match topology {
+                Some(topology) => topology.parse()?,
+                None => Topology::Bounded,
+            };
+            let (occupied, outcome) = if csv.is_none() && heatmap.is_none() && !changes {
+                simulate(&input, topology, &rules, &options)?
+            } else {
+                let grid = input.parse::<Grid>()?.with_topology(topology);
+                let (history, outcome) = grid.fixpoint_with_history(&rules, &options);
+                if let Some(path) = csv {
+                    let mut output = BufWriter::new(File::create(path)?);
+                    history.write_csv(&mut output)?;
+                    output.flush()?;
+                }
+                if let Some(path) = heatmap {
+                    let mut output = BufWriter::new(File::create(path)?);
+                    history.write_pgm(&mut output)?;
+                    output.flush()?;
+                }
+                if changes {
+                    for (round, changes) in history.changes().iter().enumerate() {
+                        println!("round {}: {} changed", round + 1, changes);
+                    }
+                }
+                (history.grid.count_eq(Cell::OccupiedSeat), outcome)
+            };
+            if print {
+                let rounds = match outcome {
+                    Outcome::Converged { rounds } | Outcome::RoundLimit { rounds } => rounds,
+                    Outcome::Cycle { start, period } => start + period,
+                };
+                let grid = input.parse::<Grid>()?.with_topology(topology);
+                print_rounds(grid, Some(rounds), |grid| grid.round(&rules));
+            }
+            println!("{}", occupied);
+            println!("{}", outcome);
+        }
+        _ => return Err(USAGE.into()),
+    }
     Ok(())
 }
 
@@ -242,12 +1144,14 @@ fn main() -> Result<(), Box<dyn Error>> {
 mod tests {
     use super::*;
     use pretty_assertions::assert_eq;
+    use std::iter;
 
     #[test]
     fn test_parse_cell() {
         assert_eq!(Ok(Cell::Floor), ".".parse());
         assert_eq!(Ok(Cell::EmptySeat), "L".parse());
         assert_eq!(Ok(Cell::OccupiedSeat), "#".parse());
+        assert_eq!(Ok(Cell::Wall), "|".parse());
         assert_eq!(Err(ParseError::BadCharacter('X')), "X".parse::<Cell>());
         assert_eq!(Err(ParseError::BadLength(0)), "".parse::<Cell>());
         assert_eq!(Err(ParseError::BadLength(2)), "..".parse::<Cell>());
@@ -258,6 +1162,7 @@ mod tests {
         assert_eq!(".", Cell::Floor.to_string());
         assert_eq!("L", Cell::EmptySeat.to_string());
         assert_eq!("#", Cell::OccupiedSeat.to_string());
+        assert_eq!("|", Cell::Wall.to_string());
     }
 
     #[test]
@@ -268,33 +1173,27 @@ mod tests {
 L.#.
 #L##
 ";
-        let grid = Grid {
-            width: 4,
-            height: 4,
-            #[rustfmt::skip]
-            cells: vector![
-                Cell::OccupiedSeat, Cell::Floor, Cell::OccupiedSeat, Cell::EmptySeat,
-                Cell::OccupiedSeat, Cell::EmptySeat, Cell::EmptySeat, Cell::EmptySeat,
-                Cell::EmptySeat, Cell::Floor, Cell::OccupiedSeat, Cell::Floor,
-                Cell::OccupiedSeat, Cell::EmptySeat, Cell::OccupiedSeat, Cell::OccupiedSeat,
-            ],
-        };
+        #[rustfmt::skip]
+        let cells = [
+            Cell::OccupiedSeat, Cell::Floor, Cell::OccupiedSeat, Cell::EmptySeat,
+            Cell::OccupiedSeat, Cell::EmptySeat, Cell::EmptySeat, Cell::EmptySeat,
+            Cell::EmptySeat, Cell::Floor, Cell::OccupiedSeat, Cell::Floor,
+            Cell::OccupiedSeat, Cell::EmptySeat, Cell::OccupiedSeat, Cell::OccupiedSeat,
+        ];
+        let grid = Grid::from_cells(4, 4, &cells);
         assert_eq!(Ok(grid), input.parse());
     }
 
     #[test]
     fn test_display_grid() {
-        let grid = Grid {
-            width: 4,
-            height: 4,
-            #[rustfmt::skip]
-            cells: vector![
-                Cell::OccupiedSeat, Cell::Floor, Cell::OccupiedSeat, Cell::EmptySeat,
-                Cell::OccupiedSeat, Cell::EmptySeat, Cell::EmptySeat, Cell::EmptySeat,
-                Cell::EmptySeat, Cell::Floor, Cell::OccupiedSeat, Cell::Floor,
-                Cell::OccupiedSeat, Cell::EmptySeat, Cell::OccupiedSeat, Cell::OccupiedSeat,
-            ],
-        };
+        #[rustfmt::skip]
+        let cells = [
+            Cell::OccupiedSeat, Cell::Floor, Cell::OccupiedSeat, Cell::EmptySeat,
+            Cell::OccupiedSeat, Cell::EmptySeat, Cell::EmptySeat, Cell::EmptySeat,
+            Cell::EmptySeat, Cell::Floor, Cell::OccupiedSeat, Cell::Floor,
+            Cell::OccupiedSeat, Cell::EmptySeat, Cell::OccupiedSeat, Cell::OccupiedSeat,
+        ];
+        let grid = Grid::from_cells(4, 4, &cells);
         let string = "\
 #.#L
 #LLL
@@ -388,6 +1287,429 @@ L.#.
         assert_eq!(0, grid_3.count_eq_visible(Cell::OccupiedSeat, 3, 3));
     }
 
+    // a floor plan where on average seats out of 8 cells are seats, half of them occupied,
+    // from a small linear congruential generator
+    fn random_grid(state: &mut u64, width: isize, height: isize, seats: u64) -> String {
+        let mut input = String::new();
+        for _ in 0..height {
+            for _ in 0..width {
+                *state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                let cell = *state >> 61;
+                input.push(if cell >= seats {
+                    '.'
+                } else if cell & 1 == 0 {
+                    'L'
+                } else {
+                    '#'
+                });
+            }
+            input.push('\n');
+        }
+        input
+    }
+
+    #[test]
+    fn test_sightlines() {
+        // the first seat along each ray, found the slow way
+        fn visible(grid: &Grid, x: isize, y: isize) -> Vec<usize> {
+            let transparent = |cell| match cell {
+                Cell::Floor => true,
+                Cell::Wall => grid.topology != Topology::Walled,
+                _ => false,
+            };
+            grid.topology
+                .directions()
+                .iter()
+                .filter_map(|&direction| {
+                    iter::successors(grid.next(x, y, direction), |&(x, y)| {
+                        grid.next(x, y, direction)
+                    })
+                    .take_while(|&position| position != (x, y))
+                    .find(|&(x, y)| !transparent(grid.cell(x, y).unwrap()))
+                    .filter(|&(x, y)| grid.cell(x, y) != Some(Cell::Wall))
+                    .map(|(x, y)| grid.index(x, y).unwrap())
+                })
+                .collect()
+        }
+
+        let mut state: u64 = 11;
+        for &(width, height) in &[(1, 1), (1, 7), (7, 1), (2, 2), (37, 23), (70, 3)] {
+            // mostly floor, to get long rays, and some walls
+            let input: String = random_grid(&mut state, width, height, 2)
+                .chars()
+                .enumerate()
+                .map(|(i, c)| if c == '.' && i % 7 == 0 { '|' } else { c })
+                .collect();
+            for &topology in &[
+                Topology::Bounded,
+                Topology::Torus,
+                Topology::Hex,
+                Topology::Walled,
+            ] {
+                let grid = input.parse::<Grid>().unwrap().with_topology(topology);
+                for y in 0..height {
+                    for x in 0..width {
+                        let index = grid.index(x, y).unwrap();
+                        assert_eq!(
+                            visible(&grid, x, y),
+                            grid.neighbors(Neighborhood::LineOfSight).of(index),
+                            "{:?} at {}, {}",
+                            topology,
+                            x,
+                            y
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_topology() {
+        assert_eq!(Ok(Topology::Bounded), "bounded".parse());
+        assert_eq!(Ok(Topology::Torus), "torus".parse());
+        assert_eq!(Ok(Topology::Hex), "hex".parse());
+        assert_eq!(Ok(Topology::Walled), "walled".parse());
+        assert_eq!(Err(ParseError::BadTopology), "sphere".parse::<Topology>());
+    }
+
+    #[test]
+    fn test_topologies() {
+        let grid: Grid = "\
+#.L
+...
+L.#
+"
+        .parse()
+        .unwrap();
+        assert_eq!(0, grid.count_eq_neighbors(Cell::OccupiedSeat, 0, 0));
+        assert_eq!(1, grid.count_eq_visible(Cell::OccupiedSeat, 0, 0));
+        assert_eq!(2, grid.count_eq_visible(Cell::EmptySeat, 0, 0));
+        // the other corners are just around the edges, and straight across too
+        let torus = grid.with_topology(Topology::Torus);
+        assert_eq!(1, torus.count_eq_neighbors(Cell::OccupiedSeat, 0, 0));
+        assert_eq!(2, torus.count_eq_neighbors(Cell::EmptySeat, 0, 0));
+        assert_eq!(2, torus.count_eq_visible(Cell::OccupiedSeat, 0, 0));
+        assert_eq!(4, torus.count_eq_visible(Cell::EmptySeat, 0, 0));
+
+        let hex = "LLL\nLLL\nLLL\n"
+            .parse::<Grid>()
+            .unwrap()
+            .with_topology(Topology::Hex);
+        assert_eq!(6, hex.count_eq_neighbors(Cell::EmptySeat, 1, 1));
+        assert_eq!(4, hex.count_eq_neighbors(Cell::EmptySeat, 1, 0));
+        assert_eq!(2, hex.count_eq_neighbors(Cell::EmptySeat, 0, 0));
+        assert_eq!(3, hex.count_eq_neighbors(Cell::EmptySeat, 2, 1));
+        // a diagonal of squares isn't a line of hexagons, which only moves over every other row
+        let hex = "L....\n.....\n..L..\n.....\n....L\n"
+            .parse::<Grid>()
+            .unwrap()
+            .with_topology(Topology::Hex);
+        assert_eq!(0, hex.count_eq_visible(Cell::EmptySeat, 0, 0));
+        assert_eq!(0, hex.count_eq_visible(Cell::EmptySeat, 2, 2));
+        let hex = "L....\n.....\n.L...\n.....\n..L..\n"
+            .parse::<Grid>()
+            .unwrap()
+            .with_topology(Topology::Hex);
+        assert_eq!(1, hex.count_eq_visible(Cell::EmptySeat, 0, 0));
+        assert_eq!(2, hex.count_eq_visible(Cell::EmptySeat, 1, 2));
+
+        let grid: Grid = "L|L\n".parse().unwrap();
+        assert_eq!(1, grid.count_eq(Cell::Wall));
+        assert_eq!(0, grid.count_eq(Cell::Floor));
+        assert_eq!(2, grid.count_eq(Cell::EmptySeat));
+        assert_eq!("L|L\n", grid.to_string());
+        assert_eq!(1, grid.count_eq_visible(Cell::EmptySeat, 0, 0));
+        let walled = grid.with_topology(Topology::Walled);
+        assert_eq!(0, walled.count_eq_visible(Cell::EmptySeat, 0, 0));
+        assert_eq!(1, walled.count_eq_neighbors(Cell::Wall, 0, 0));
+
+        // without edges, every seat has all eight neighbors, so nobody can stay
+        let grid: Grid = "LLL\nLLL\nLLL\n".parse().unwrap();
+        let (last, outcome) = grid.fixpoint(&SeatingRules::PART1, &SimulationOptions::default());
+        assert_eq!(Outcome::Converged { rounds: 2 }, outcome);
+        assert_eq!("#L#\nLLL\n#L#\n", last.to_string());
+        let torus = grid.with_topology(Topology::Torus);
+        let (_last, outcome) = torus.fixpoint(&SeatingRules::PART1, &SimulationOptions::default());
+        assert_eq!(
+            Outcome::Cycle {
+                start: 0,
+                period: 2
+            },
+            outcome
+        );
+    }
+
+    #[test]
+    fn test_thin_torus() {
+        // a torus one cell wide leads back to the same cell, which doesn't count as its own neighbor
+        let torus = "#\nL\n.\n"
+            .parse::<Grid>()
+            .unwrap()
+            .with_topology(Topology::Torus);
+        assert_eq!(0, torus.count_eq_neighbors(Cell::OccupiedSeat, 0, 0));
+        assert_eq!(3, torus.count_eq_neighbors(Cell::EmptySeat, 0, 0));
+        assert_eq!(3, torus.count_eq_neighbors(Cell::Floor, 0, 0));
+        assert_eq!(6, torus.count_eq_visible(Cell::OccupiedSeat, 0, 1));
+        assert_eq!(0, torus.count_eq_visible(Cell::EmptySeat, 0, 1));
+
+        let torus = "L#.\n"
+            .parse::<Grid>()
+            .unwrap()
+            .with_topology(Topology::Torus);
+        assert_eq!(3, torus.count_eq_neighbors(Cell::OccupiedSeat, 0, 0));
+        assert_eq!(0, torus.count_eq_neighbors(Cell::EmptySeat, 0, 0));
+        assert_eq!(3, torus.count_eq_neighbors(Cell::Floor, 0, 0));
+
+        // and a single seat on its own has no neighbors at all
+        let torus = "L\n"
+            .parse::<Grid>()
+            .unwrap()
+            .with_topology(Topology::Torus);
+        assert_eq!(0, torus.count_eq_neighbors(Cell::EmptySeat, 0, 0));
+        assert_eq!("#\n", torus.round_part1().to_string());
+        let (last, outcome) = torus.fixpoint(&SeatingRules::PART1, &SimulationOptions::default());
+        assert_eq!(Outcome::Converged { rounds: 1 }, outcome);
+        assert_eq!(last, torus.round_part1());
+    }
+
+    #[test]
+    fn test_adjacent_across_words() {
+        // the first seat within one cell is an adjacent seat, but looked up one by one
+        // instead of counted with shifts
+        let mut state = 21;
+        let grid: Grid = random_grid(&mut state, 150, 9, 7).parse().unwrap();
+        for &(occupy_threshold, vacate_threshold) in &[(0, 4), (1, 3), (2, 2), (8, 9)] {
+            let shifted = SeatingRules {
+                neighborhood: Neighborhood::Adjacent,
+                occupy_threshold,
+                vacate_threshold,
+            };
+            let looked_up = SeatingRules {
+                neighborhood: Neighborhood::LineOfSightWithin(1),
+                ..shifted
+            };
+            let (mut a, mut b) = (grid.round(&shifted), grid.round(&looked_up));
+            for _ in 0..5 {
+                assert_eq!(a, b);
+                a = a.round(&shifted);
+                b = b.round(&looked_up);
+            }
+        }
+    }
+
+    #[test]
+    fn test_threads() {
+        let mut state = 31;
+        let grid: Grid = random_grid(&mut state, 130, 23, 7).parse().unwrap();
+        let restless = SeatingRules {
+            neighborhood: Neighborhood::LineOfSightWithin(2),
+            occupy_threshold: 1,
+            vacate_threshold: 3,
+        };
+        for rules in &[SeatingRules::PART1, SeatingRules::PART2, restless] {
+            let expected = grid.fixpoint(rules, &SimulationOptions::default());
+            // including more threads than rows, and bands of different sizes
+            for &threads in &[0, 2, 4, 7, 23, 100] {
+                let options = SimulationOptions {
+                    threads,
+                    ..SimulationOptions::default()
+                };
+                assert_eq!(expected, grid.fixpoint(rules, &options));
+            }
+        }
+        let empty: Grid = "".parse().unwrap();
+        let options = SimulationOptions {
+            threads: 4,
+            ..SimulationOptions::default()
+        };
+        assert_eq!(
+            Outcome::Converged { rounds: 0 },
+            empty.fixpoint(&SeatingRules::PART2, &options).1
+        );
+    }
+
+    #[test]
+    fn test_incremental() {
+        let mut state = 41;
+        // with a wall for every now and then
+        let input = random_grid(&mut state, 90, 70, 6).replace("..", ".|");
+        let restless = SeatingRules {
+            neighborhood: Neighborhood::LineOfSightWithin(3),
+            occupy_threshold: 1,
+            vacate_threshold: 3,
+        };
+        let full = SimulationOptions {
+            incremental: false,
+            ..SimulationOptions::default()
+        };
+        for &topology in &[
+            Topology::Bounded,
+            Topology::Torus,
+            Topology::Hex,
+            Topology::Walled,
+        ] {
+            let grid = input.parse::<Grid>().unwrap().with_topology(topology);
+            for rules in &[SeatingRules::PART1, SeatingRules::PART2, restless] {
+                // the same seats change in every round, just found with less work
+                let (expected, expected_outcome) = grid.fixpoint_with_history(rules, &full);
+                let (history, outcome) =
+                    grid.fixpoint_with_history(rules, &SimulationOptions::default());
+                assert_eq!(expected_outcome, outcome, "{:?} {:?}", topology, rules);
+                assert_eq!(expected.grid, history.grid);
+                assert_eq!(expected.changes(), history.changes());
+                let mut csv = (Vec::new(), Vec::new());
+                expected.write_csv(&mut csv.0).unwrap();
+                history.write_csv(&mut csv.1).unwrap();
+                assert_eq!(csv.0, csv.1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_neighborhood() {
+        assert_eq!(Ok(Neighborhood::Adjacent), "adjacent".parse());
+        assert_eq!(Ok(Neighborhood::LineOfSight), "sight".parse());
+        assert_eq!(Ok(Neighborhood::LineOfSightWithin(3)), "sight:3".parse());
+        assert_eq!(
+            Err(ParseError::BadNeighborhood),
+            "sight:-1".parse::<Neighborhood>()
+        );
+        assert_eq!(
+            Err(ParseError::BadNeighborhood),
+            "near".parse::<Neighborhood>()
+        );
+    }
+
+    #[test]
+    fn test_seating_rules() {
+        let input = "\
+L.LL.LL.LL
+LLLLLLL.LL
+L.L.L..L..
+LLLL.LL.LL
+L.LL.LL.LL
+L.LLLLL.LL
+..L.L.....
+LLLLLLLLLL
+L.LLLLLL.L
+L.LLLLL.LL
+";
+        let rules = |neighborhood, occupy_threshold, vacate_threshold| SeatingRules {
+            neighborhood,
+            occupy_threshold,
+            vacate_threshold,
+        };
+        let occupied = |rules| {
+            simulate(
+                input,
+                Topology::Bounded,
+                &rules,
+                &SimulationOptions::default(),
+            )
+            .unwrap()
+            .0
+        };
+        // seeing one cell far is the same as looking at the adjacent cells,
+        // and the example room is too small to tell ten cells from any distance
+        assert_eq!(
+            37,
+            occupied(rules(Neighborhood::LineOfSightWithin(1), 0, 4))
+        );
+        assert_eq!(
+            26,
+            occupied(rules(Neighborhood::LineOfSightWithin(10), 0, 5))
+        );
+        // nobody ever leaves, so everyone who sits down in the first round stays
+        assert_eq!(71, occupied(rules(Neighborhood::Adjacent, 0, 9)));
+
+        let grid: Grid = "L.L..L\n".parse().unwrap();
+        let near = grid.round(&rules(Neighborhood::LineOfSightWithin(2), 0, 1));
+        assert_eq!("#.#..#\n", near.to_string());
+        let near = near.round(&rules(Neighborhood::LineOfSightWithin(2), 0, 1));
+        assert_eq!("L.L..#\n", near.to_string());
+        // with a more tolerant threshold, the first two seats stay
+        let grid: Grid = "#.#..#\n".parse().unwrap();
+        let tolerant = grid.round(&rules(Neighborhood::LineOfSightWithin(2), 0, 2));
+        assert_eq!("#.#..#\n", tolerant.to_string());
+    }
+
+    #[test]
+    fn test_fixpoint_outcomes() {
+        let capped = |max_rounds| SimulationOptions {
+            max_rounds: Some(max_rounds),
+            ..SimulationOptions::default()
+        };
+        let example: Grid = "\
+L.LL.LL.LL
+LLLLLLL.LL
+L.L.L..L..
+LLLL.LL.LL
+L.LL.LL.LL
+L.LLLLL.LL
+..L.L.....
+LLLLLLLLLL
+L.LLLLLL.L
+L.LLLLL.LL
+"
+        .parse()
+        .unwrap();
+        let (_fix, outcome) = example.fixpoint(&SeatingRules::PART1, &SimulationOptions::default());
+        assert_eq!(Outcome::Converged { rounds: 5 }, outcome);
+        // a cap at the last round still sees that the grid has settled
+        let (fix, outcome) = example.fixpoint(&SeatingRules::PART1, &capped(5));
+        assert_eq!(Outcome::Converged { rounds: 5 }, outcome);
+        assert_eq!(fix, fix.round_part1());
+        let (last, outcome) = example.fixpoint(&SeatingRules::PART1, &capped(4));
+        assert_eq!(Outcome::RoundLimit { rounds: 4 }, outcome);
+        assert_ne!(last, fix);
+        assert_eq!(last.round_part1(), fix);
+        let (_fix, outcome) = example.fixpoint(&SeatingRules::PART2, &SimulationOptions::default());
+        assert_eq!(Outcome::Converged { rounds: 6 }, outcome);
+        let (last, outcome) = example.fixpoint(&SeatingRules::PART2, &capped(2));
+        assert_eq!(Outcome::RoundLimit { rounds: 2 }, outcome);
+        assert_eq!(example.round_part2().round_part2(), last);
+
+        // everyone sits down, then everyone is next to someone and leaves
+        let restless = SeatingRules {
+            neighborhood: Neighborhood::Adjacent,
+            occupy_threshold: 0,
+            vacate_threshold: 1,
+        };
+        let grid: Grid = "LL\n".parse().unwrap();
+        let (last, outcome) = grid.fixpoint(&restless, &SimulationOptions::default());
+        assert_eq!(
+            Outcome::Cycle {
+                start: 0,
+                period: 2
+            },
+            outcome
+        );
+        assert_eq!("LL\n", last.to_string());
+
+        let restless = SeatingRules {
+            occupy_threshold: 1,
+            ..restless
+        };
+        let grid: Grid = "L#L#L#\n".parse().unwrap();
+        let (last, outcome) = grid.fixpoint(&restless, &SimulationOptions::default());
+        assert_eq!(
+            Outcome::Cycle {
+                start: 5,
+                period: 2
+            },
+            outcome
+        );
+        let mut start = grid.round(&restless);
+        for _ in 1..5 {
+            start = start.round(&restless);
+        }
+        assert_eq!(start, last);
+        assert_ne!(start, start.round(&restless));
+        let (_last, outcome) = grid.fixpoint(&restless, &capped(4));
+        assert_eq!(Outcome::RoundLimit { rounds: 4 }, outcome);
+    }
+
     #[test]
     fn test_grid_round_part1() {
         let round_0 = "\
@@ -525,7 +1847,7 @@ LLLLLLLLLL
 L.LLLLLL.L
 L.LLLLL.LL
 ";
-        assert_eq!(37, part1(&input).unwrap());
+        assert_eq!(37, part1(input).unwrap());
     }
 
     #[test]
@@ -679,6 +2001,6 @@ LLLLLLLLLL
 L.LLLLLL.L
 L.LLLLL.LL
 ";
-        assert_eq!(26, part2(&input).unwrap());
+        assert_eq!(26, part2(input).unwrap());
     }
 }